@@ -0,0 +1,186 @@
+/// A growable bitfield, laid out the same way as hypercore's: bit `i` is the most significant
+/// bit of byte `i / 8` first.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct Bitfield {
+    bytes: Vec<u8>,
+}
+
+impl Bitfield {
+    pub fn new() -> Self {
+        Bitfield::default()
+    }
+
+    pub fn from_bytes(bytes: Vec<u8>) -> Self {
+        Bitfield { bytes }
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    pub fn get(&self, index: u64) -> bool {
+        match self.bytes.get((index / 8) as usize) {
+            Some(byte) => byte & mask(index) != 0,
+            None => false,
+        }
+    }
+
+    /// Returns whether the bit changed.
+    pub fn set(&mut self, index: u64, value: bool) -> bool {
+        let i = (index / 8) as usize;
+        if i >= self.bytes.len() {
+            if !value {
+                return false;
+            }
+            self.bytes.resize(i + 1, 0);
+        }
+
+        let old = self.bytes[i];
+        if value {
+            self.bytes[i] |= mask(index);
+        } else {
+            self.bytes[i] &= !mask(index);
+        }
+        old != self.bytes[i]
+    }
+
+    /// Sets `length` bits from `start`, whole bytes at once.
+    pub fn set_range(&mut self, start: u64, length: u64, value: bool) {
+        let mut end = start.saturating_add(length);
+        if !value {
+            // Nothing to clear past the end
+            end = end.min(self.bytes.len() as u64 * 8);
+        }
+        if start >= end {
+            return;
+        }
+        if value && end > self.bytes.len() as u64 * 8 {
+            self.bytes.resize(end.div_ceil(8) as usize, 0);
+        }
+
+        let first = (start / 8) as usize;
+        let last = ((end - 1) / 8) as usize;
+        let head = 0xff >> (start & 7);
+        let tail = 0xff << (7 - ((end - 1) & 7));
+        if first == last {
+            self.apply(first, head & tail, value);
+            return;
+        }
+        self.apply(first, head, value);
+        for byte in &mut self.bytes[first + 1..last] {
+            *byte = if value { 0xff } else { 0 };
+        }
+        self.apply(last, tail, value);
+    }
+
+    /// Overwrites the bits from `start` with `bytes`, which are laid out the same way.
+    pub fn set_bytes(&mut self, start: u64, bytes: &[u8]) {
+        // Trailing zeros only clear bits, they need no room
+        let used = match bytes.iter().rposition(|byte| *byte != 0) {
+            Some(i) => i + 1,
+            None => 0,
+        };
+        let end = start + used as u64 * 8;
+        if end > self.bytes.len() as u64 * 8 {
+            self.bytes.resize(end.div_ceil(8) as usize, 0);
+        }
+
+        let first = (start / 8) as usize;
+        let shift = (start & 7) as u32;
+        if shift == 0 {
+            let len = bytes.len().min(self.bytes.len().saturating_sub(first));
+            self.bytes[first..first + len].copy_from_slice(&bytes[..len]);
+            return;
+        }
+        for (i, byte) in bytes.iter().enumerate() {
+            let i = first + i;
+            if i >= self.bytes.len() {
+                break;
+            }
+            self.bytes[i] = self.bytes[i] & !(0xff >> shift) | byte >> shift;
+            if let Some(next) = self.bytes.get_mut(i + 1) {
+                *next = *next & !(0xff << (8 - shift)) | byte << (8 - shift);
+            }
+        }
+    }
+
+    fn apply(&mut self, i: usize, mask: u8, value: bool) {
+        if value {
+            self.bytes[i] |= mask;
+        } else {
+            self.bytes[i] &= !mask;
+        }
+    }
+
+    /// The index after the last set bit.
+    pub fn length(&self) -> u64 {
+        match self.bytes.iter().rposition(|byte| *byte != 0) {
+            Some(i) => i as u64 * 8 + 8 - u64::from(self.bytes[i].trailing_zeros()),
+            None => 0,
+        }
+    }
+}
+
+fn mask(index: u64) -> u8 {
+    128 >> (index & 7)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_set_and_get() {
+        let mut bitfield = Bitfield::new();
+        assert!(!bitfield.get(9));
+        assert!(bitfield.set(9, true));
+        assert!(!bitfield.set(9, true));
+        assert!(bitfield.get(9));
+        assert!(!bitfield.get(8));
+        assert_eq!(bitfield.as_bytes(), &[0b0000_0000, 0b0100_0000]);
+        assert_eq!(bitfield.length(), 10);
+
+        assert!(bitfield.set(9, false));
+        assert!(!bitfield.set(100, false));
+        assert_eq!(bitfield.length(), 0);
+    }
+
+    #[test]
+    fn test_set_range() {
+        let mut bitfield = Bitfield::new();
+        bitfield.set_range(3, 2, true);
+        assert_eq!(bitfield.as_bytes(), &[0b0001_1000]);
+        bitfield.set_range(6, 20, true);
+        assert_eq!(bitfield.as_bytes(), &[0b0001_1011, 0xff, 0xff, 0b1100_0000]);
+        assert_eq!(bitfield.length(), 26);
+
+        bitfield.set_range(4, 16, false);
+        assert_eq!(
+            bitfield.as_bytes(),
+            &[0b0001_0000, 0, 0b0000_1111, 0b1100_0000]
+        );
+        bitfield.set_range(20, 1 << 40, false);
+        assert_eq!(bitfield.length(), 4);
+        assert_eq!(bitfield.as_bytes().len(), 4);
+
+        let mut bitfield = Bitfield::new();
+        bitfield.set_range(0, 1 << 20, true);
+        assert_eq!(bitfield.length(), 1 << 20);
+        assert!(bitfield.as_bytes().iter().all(|byte| *byte == 0xff));
+    }
+
+    #[test]
+    fn test_set_bytes() {
+        let mut bitfield = Bitfield::new();
+        bitfield.set_range(0, 24, true);
+        bitfield.set_bytes(8, &[0b1010_0000, 0]);
+        assert_eq!(bitfield.as_bytes(), &[0xff, 0b1010_0000, 0]);
+
+        bitfield.set_bytes(4, &[0b0101_1111]);
+        assert_eq!(bitfield.as_bytes(), &[0b1111_0101, 0b1111_0000, 0]);
+
+        let mut bitfield = Bitfield::new();
+        bitfield.set_bytes(3, &[0xff, 0, 0]);
+        assert_eq!(bitfield.as_bytes(), &[0b0001_1111, 0b1110_0000]);
+    }
+}
@@ -0,0 +1,198 @@
+// Codec for the run-length encoding of https://github.com/mafintosh/bitfield-rle, used for the
+// `bitfield` of `Have` messages.
+//
+// The encoded bitfield is a sequence of varint headers. If the lowest bit of a header is set,
+// it describes a run of `header >> 2` bytes that are all 0xff (if bit 1 is set) or all 0x00.
+// Otherwise `header >> 1` literal bytes follow the header.
+
+use std::fmt::{self, Display, Formatter};
+
+use integer_encoding::VarInt;
+
+use crate::schema;
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct InvalidBitfield;
+
+impl Display for InvalidBitfield {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "Invalid RLE bitfield")
+    }
+}
+
+/// Encodes the same way as the reference implementation: trailing zeros are left out, and a run
+/// of 0x00 or 0xff bytes is only split off a literal if that makes the encoding shorter.
+pub fn encode(bitfield: &[u8]) -> Vec<u8> {
+    let end = match bitfield.iter().rposition(|byte| *byte != 0) {
+        Some(i) => i + 1,
+        None => 0,
+    };
+    let bitfield = &bitfield[..end];
+
+    let mut encoded = Vec::new();
+    // Start of the bytes that are not encoded yet
+    let mut offset = 0;
+    let (mut run_byte, mut run_length) = (0x00, 0);
+    for (i, byte) in bitfield.iter().enumerate() {
+        if *byte == run_byte {
+            run_length += 1;
+            continue;
+        }
+        if run_length > 0 {
+            offset = encode_run(&mut encoded, bitfield, offset, i, run_byte, run_length);
+        }
+        if *byte == 0x00 || *byte == 0xff {
+            run_byte = *byte;
+            run_length = 1;
+        } else {
+            run_length = 0;
+        }
+    }
+    if run_length > 0 {
+        offset = encode_run(&mut encoded, bitfield, offset, end, run_byte, run_length);
+    }
+    write_literal(&mut encoded, &bitfield[offset..]);
+    encoded
+}
+
+/// Writes the literal before the run of `length` bytes ending at `end` and the run, if that is
+/// shorter than a literal of all of them. Returns the new start of the bytes not encoded yet.
+fn encode_run(
+    encoded: &mut Vec<u8>,
+    bitfield: &[u8],
+    offset: usize,
+    end: usize,
+    byte: u8,
+    length: usize,
+) -> usize {
+    let literal = end - length - offset;
+    let header = (length as u64) << 2 | if byte == 0xff { 2 } else { 0 } | 1;
+    let cost = literal_cost(literal) + header.required_space();
+    if cost >= literal_cost(end - offset) {
+        return offset;
+    }
+    write_literal(encoded, &bitfield[offset..end - length]);
+    write_varint(encoded, header);
+    end
+}
+
+fn literal_cost(length: usize) -> usize {
+    if length == 0 {
+        0
+    } else {
+        ((length as u64) << 1).required_space() + length
+    }
+}
+
+/// Fails if `bytes` is not a valid encoding, or if the decoded bitfield would be longer than
+/// `max_length` bytes.
+pub fn decode(bytes: &[u8], max_length: usize) -> Result<Vec<u8>, InvalidBitfield> {
+    let mut bitfield = Vec::new();
+    let mut offset = 0;
+    while offset < bytes.len() {
+        // A u64 takes at most 10 bytes, `decode_var` overflows when given more.
+        let header_bytes = &bytes[offset..bytes.len().min(offset + 10)];
+        let (header, read) = u64::decode_var(header_bytes);
+        if header_bytes[read - 1] & 0x80 != 0 {
+            return Err(InvalidBitfield);
+        }
+        offset += read;
+
+        let repeat = header & 1 != 0;
+        let length = if repeat { header >> 2 } else { header >> 1 } as usize;
+        if length > max_length - bitfield.len() {
+            return Err(InvalidBitfield);
+        }
+
+        if repeat {
+            let byte = if header & 2 != 0 { 0xff } else { 0x00 };
+            bitfield.resize(bitfield.len() + length, byte);
+        } else {
+            if length > bytes.len() - offset {
+                return Err(InvalidBitfield);
+            }
+            bitfield.extend_from_slice(&bytes[offset..offset + length]);
+            offset += length;
+        }
+    }
+    Ok(bitfield)
+}
+
+/// A `Have` message announcing the blocks set in `bitfield`, bit 0 being block `start`.
+pub fn have(start: u64, bitfield: &[u8]) -> schema::Have {
+    let mut have = schema::Have::new();
+    have.set_start(start);
+    have.set_bitfield(encode(bitfield));
+    have
+}
+
+fn write_literal(encoded: &mut Vec<u8>, bytes: &[u8]) {
+    if !bytes.is_empty() {
+        write_varint(encoded, (bytes.len() as u64) << 1);
+        encoded.extend_from_slice(bytes);
+    }
+}
+
+fn write_varint(encoded: &mut Vec<u8>, value: u64) {
+    encoded.extend_from_slice(&value.encode_var_vec());
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::Rng;
+
+    use super::*;
+
+    // The encoding of https://github.com/mafintosh/bitfield-rle (index.js), which only writes a
+    // run where it is shorter than leaving its bytes in the surrounding literal.
+    #[test]
+    fn test_encode() {
+        assert_eq!(encode(&[]), Vec::<u8>::new());
+        assert_eq!(encode(&[0x00; 1024]), Vec::<u8>::new());
+        assert_eq!(encode(&[0xff; 128]), vec![0x83, 0x04]);
+        let mut zeros = vec![0x00; 1024];
+        zeros.push(1);
+        assert_eq!(encode(&zeros), vec![0x81, 0x20, 0x02, 0x01]);
+        assert_eq!(
+            encode(&[0xff, 0xff, 0xff, 0xff, 0b1010_0000, 0, 0, 0, 0, 0, 0, 0, 0]),
+            vec![0x13, 0x02, 0b1010_0000]
+        );
+        assert_eq!(encode(&[1, 0, 2]), vec![0x06, 1, 0, 2]);
+        assert_eq!(encode(&[0xff, 0x12, 0xff]), vec![0x07, 0x04, 0x12, 0xff]);
+        let mut bitfield = vec![0xff, 0xff, 0x07];
+        bitfield.extend_from_slice(&[0x00; 18]);
+        bitfield.push(0x80);
+        assert_eq!(encode(&bitfield), vec![0x0b, 0x02, 0x07, 0x49, 0x02, 0x80]);
+    }
+
+    #[test]
+    fn test_decode() {
+        // 2 bytes of 0xff, 1 literal byte, 3 bytes of 0x00
+        let encoded = [0b0000_1011, 0b0000_0010, 0b1010_0000, 0b0000_1101];
+        assert_eq!(
+            decode(&encoded, 1024),
+            Ok(vec![0xff, 0xff, 0b1010_0000, 0, 0, 0])
+        );
+        assert_eq!(decode(&encoded, 5), Err(InvalidBitfield));
+        assert_eq!(decode(&encoded[..2], 1024), Err(InvalidBitfield));
+        assert_eq!(decode(&[0xff; 12], 1024), Err(InvalidBitfield));
+    }
+
+    #[test]
+    fn test_roundtrip() {
+        let mut rng = rand::thread_rng();
+        for _ in 0..100 {
+            let bitfield = (0..rng.gen_range(0, 1024))
+                .map(|_| match rng.gen_range(0, 4) {
+                    0 => 0x00,
+                    1 => 0xff,
+                    _ => rng.gen(),
+                })
+                .collect::<Vec<u8>>();
+            // Trailing zeros are implied
+            let mut decoded = decode(&encode(&bitfield), bitfield.len()).unwrap();
+            decoded.resize(bitfield.len(), 0x00);
+            assert_eq!(decoded, bitfield);
+        }
+    }
+}
@@ -0,0 +1,293 @@
+use std::any::Any;
+use std::cell::RefCell;
+use std::fmt::{self, Debug, Formatter};
+use std::rc::Rc;
+
+use crate::protocol::{DiscoveryKey, Key};
+use crate::remote_state::RemoteState;
+
+/// A custom message type, negotiated by name in the handshake and sent on the feeds.
+///
+/// Registered with `ProtocolBuilder::register`. Messages are only sent if both sides
+/// registered (or at least named) the extension.
+pub trait Extension: 'static {
+    type Message;
+
+    fn name(&self) -> &str;
+
+    fn encode(&self, message: &Self::Message) -> Vec<u8>;
+
+    /// `None` if the payload is invalid, the message is dropped then.
+    fn decode(&self, payload: &[u8]) -> Option<Self::Message>;
+
+    fn on_message(&mut self, feed: &mut dyn ExtensionFeed, message: Self::Message);
+}
+
+/// What an `Extension` handler can do with the feed the message arrived on.
+pub trait ExtensionFeed {
+    fn key(&self) -> Option<&Key>;
+
+    fn discovery_key(&self) -> Option<&DiscoveryKey>;
+
+    fn remote(&self) -> &RemoteState;
+
+    /// Sends the encoded message of an extension, `false` if the remote does not support it.
+    fn extension(&mut self, name: &str, payload: &[u8]) -> bool;
+}
+
+/// A custom message type sent on the connection rather than on a feed, usable as soon as
+/// the handshake is done.
+///
+/// Registered with `ProtocolBuilder::register_connection`, sent with
+/// `Protocol::send_extension`. The messages go on the channel of the handshake.
+pub trait ConnectionExtension: 'static {
+    type Message;
+
+    fn name(&self) -> &str;
+
+    fn encode(&self, message: &Self::Message) -> Vec<u8>;
+
+    /// `None` if the payload is invalid, the message is dropped then.
+    fn decode(&self, payload: &[u8]) -> Option<Self::Message>;
+
+    fn on_message(&mut self, connection: &mut dyn ExtensionConnection, message: Self::Message);
+}
+
+/// What a `ConnectionExtension` handler can do with the connection.
+pub trait ExtensionConnection {
+    /// Sends the encoded message of an extension, `false` if the remote does not support it.
+    fn extension(&mut self, name: &str, payload: &[u8]) -> bool;
+}
+
+pub(crate) trait Handler {
+    /// `false` if the payload could not be decoded.
+    fn on_payload(&mut self, feed: &mut dyn ExtensionFeed, payload: &[u8]) -> bool;
+
+    fn as_any(&self) -> &dyn Any;
+}
+
+impl<X: Extension> Handler for X {
+    fn on_payload(&mut self, feed: &mut dyn ExtensionFeed, payload: &[u8]) -> bool {
+        match self.decode(payload) {
+            Some(message) => {
+                self.on_message(feed, message);
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+pub(crate) trait ConnectionHandler {
+    /// `false` if the payload could not be decoded.
+    fn on_payload(&mut self, connection: &mut dyn ExtensionConnection, payload: &[u8]) -> bool;
+
+    fn as_any(&self) -> &dyn Any;
+}
+
+impl<X: ConnectionExtension> ConnectionHandler for X {
+    fn on_payload(&mut self, connection: &mut dyn ExtensionConnection, payload: &[u8]) -> bool {
+        match self.decode(payload) {
+            Some(message) => {
+                self.on_message(connection, message);
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+/// How the messages of an extension are handled.
+#[derive(Clone)]
+pub(crate) enum Entry {
+    /// Emitted as `FeedEvent::Extension`.
+    Event,
+    Feed(Rc<RefCell<dyn Handler>>),
+    Connection(Rc<RefCell<dyn ConnectionHandler>>),
+}
+
+impl Entry {
+    pub(crate) fn feed<X: Extension>(extension: X) -> (String, Entry) {
+        let name = Extension::name(&extension).to_owned();
+        (name, Entry::Feed(Rc::new(RefCell::new(extension))))
+    }
+
+    pub(crate) fn connection<X: ConnectionExtension>(extension: X) -> (String, Entry) {
+        let name = ConnectionExtension::name(&extension).to_owned();
+        (name, Entry::Connection(Rc::new(RefCell::new(extension))))
+    }
+
+    fn is_event(&self) -> bool {
+        matches!(self, Entry::Event)
+    }
+}
+
+/// The local extensions, sorted by name, as sent in the handshake. The index of an
+/// extension in this list is its id on the wire.
+#[derive(Clone, Default)]
+pub struct Extensions {
+    names: Vec<String>,
+    entries: Vec<Entry>,
+}
+
+impl Debug for Extensions {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        f.debug_list().entries(&self.names).finish()
+    }
+}
+
+impl Extensions {
+    /// `Err` with the name registered twice.
+    pub(crate) fn new(names: Vec<String>, handlers: Vec<(String, Entry)>) -> Result<Self, String> {
+        let mut extensions = names
+            .into_iter()
+            .map(|name| (name, Entry::Event))
+            .chain(handlers)
+            .collect::<Vec<_>>();
+        // Handlers go after the plain names with the same name
+        extensions.sort_by(|(a, a_entry), (b, b_entry)| {
+            a.cmp(b).then(b_entry.is_event().cmp(&a_entry.is_event()))
+        });
+        for pair in extensions.windows(2) {
+            if !pair[0].1.is_event() && pair[0].0 == pair[1].0 {
+                return Err(pair[0].0.clone());
+            }
+        }
+        // Keeps the last, i.e. the handler if there is one
+        extensions.reverse();
+        extensions.dedup_by(|a, b| a.0 == b.0);
+        extensions.reverse();
+
+        let (names, entries) = extensions.into_iter().unzip();
+        Ok(Extensions { names, entries })
+    }
+
+    pub(crate) fn names(&self) -> &[String] {
+        &self.names
+    }
+
+    pub(crate) fn id(&self, name: &str) -> Option<usize> {
+        self.names
+            .binary_search_by(|probe| probe.as_str().cmp(name))
+            .ok()
+    }
+
+    pub(crate) fn entry(&self, id: usize) -> Option<Entry> {
+        self.entries.get(id).cloned()
+    }
+
+    /// The id and the handler of the extension of type `X`.
+    pub(crate) fn find<X: Extension>(&self) -> Option<(usize, Rc<RefCell<dyn Handler>>)> {
+        self.entries
+            .iter()
+            .enumerate()
+            .find_map(|(id, entry)| match entry {
+                Entry::Feed(handler) if handler.borrow().as_any().is::<X>() => {
+                    Some((id, handler.clone()))
+                }
+                _ => None,
+            })
+    }
+
+    /// The id and the handler of the connection extension of type `X`.
+    pub(crate) fn find_connection<X: ConnectionExtension>(
+        &self,
+    ) -> Option<(usize, Rc<RefCell<dyn ConnectionHandler>>)> {
+        self.entries
+            .iter()
+            .enumerate()
+            .find_map(|(id, entry)| match entry {
+                Entry::Connection(handler) if handler.borrow().as_any().is::<X>() => {
+                    Some((id, handler.clone()))
+                }
+                _ => None,
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Named(&'static str);
+
+    impl Extension for Named {
+        type Message = ();
+
+        fn name(&self) -> &str {
+            self.0
+        }
+
+        fn encode(&self, _message: &()) -> Vec<u8> {
+            Vec::new()
+        }
+
+        fn decode(&self, _payload: &[u8]) -> Option<()> {
+            Some(())
+        }
+
+        fn on_message(&mut self, _feed: &mut dyn ExtensionFeed, _message: ()) {}
+    }
+
+    struct Connection;
+
+    impl ConnectionExtension for Connection {
+        type Message = ();
+
+        fn name(&self) -> &str {
+            "c"
+        }
+
+        fn encode(&self, _message: &()) -> Vec<u8> {
+            Vec::new()
+        }
+
+        fn decode(&self, _payload: &[u8]) -> Option<()> {
+            Some(())
+        }
+
+        fn on_message(&mut self, _connection: &mut dyn ExtensionConnection, _message: ()) {}
+    }
+
+    #[test]
+    fn test_extensions() {
+        let extensions = Extensions::new(
+            vec![
+                "c".to_owned(),
+                "a".to_owned(),
+                "c".to_owned(),
+                "b".to_owned(),
+            ],
+            vec![Entry::feed(Named("b")), Entry::connection(Connection)],
+        )
+        .unwrap();
+        assert_eq!(extensions.names(), ["a", "b", "c"]);
+        assert_eq!(extensions.id("c"), Some(2));
+        assert_eq!(extensions.id("d"), None);
+        assert!(matches!(extensions.entry(0), Some(Entry::Event)));
+        assert!(matches!(extensions.entry(1), Some(Entry::Feed(_))));
+        assert!(matches!(extensions.entry(2), Some(Entry::Connection(_))));
+        assert_eq!(extensions.find::<Named>().map(|(id, _)| id), Some(1));
+        assert_eq!(
+            extensions.find_connection::<Connection>().map(|(id, _)| id),
+            Some(2)
+        );
+
+        assert_eq!(
+            Extensions::new(
+                vec![],
+                vec![Entry::feed(Named("c")), Entry::connection(Connection)]
+            )
+            .err(),
+            Some("c".to_owned())
+        );
+    }
+}
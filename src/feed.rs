@@ -1,18 +1,54 @@
+use std::collections::HashSet;
 use std::fmt::{Debug, Error, Formatter};
+use std::rc::Rc;
+use std::time::Instant;
 
-use slog::{o, trace, Drain, Logger};
+use integer_encoding::VarInt;
 
+use crate::extension::{Entry, Extension, ExtensionConnection, ExtensionFeed, Extensions};
+use crate::logging::{trace, Bytes, Log, Payload};
+use crate::merkle::{Verifier, VerifyError};
 use crate::protocol::{Channel, DiscoveryKey, Key, Message, MessageType};
+use crate::remote_state::RemoteState;
+use crate::requests::{RequestError, Requests};
 use crate::schema;
+use crate::stats::FeedStats;
+use crate::storage::{FeedStorage, StorageError};
 use crate::wire_format::{self, write_msg};
 
 pub trait FeedStream {
-    fn _push(&mut self, bytes: &[u8]);
+    /// Returns `false` if the caller should wait for a `FeedEvent::Drain` before sending more.
+    fn _push(&mut self, bytes: &[u8]) -> bool;
+    /// Both sides agreed to acknowledge every `Data`.
+    fn _ack(&self) -> bool;
+    fn _extensions(&self) -> Rc<Extensions>;
+    /// The local id of the extension with this id on the remote, if both sides have it.
+    fn _local_extension(&self, remote_id: u64) -> Option<usize>;
+    fn _remote_supports(&self, id: usize) -> bool;
+    /// Sends an extension message on the channel of the handshake.
+    fn _connection_extension(&mut self, id: usize, payload: &[u8]) -> bool;
     fn _onhandshake(&mut self, handshake: &schema::Handshake);
+    fn _destroy(&mut self, err: &str);
+}
+
+/// What a side is doing on a feed, sent in `Info` messages. Both are initially true.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct Info {
+    pub uploading: bool,
+    pub downloading: bool,
+}
+
+impl Default for Info {
+    fn default() -> Self {
+        Info {
+            uploading: true,
+            downloading: true,
+        }
+    }
 }
 
 pub struct Feed<FS: FeedStream, E: FeedEventEmitter> {
-    log: Logger,
+    log: Log,
 
     pub(crate) key: Option<Key>,
     pub(crate) discovery_key: Option<DiscoveryKey>,
@@ -24,6 +60,14 @@ pub struct Feed<FS: FeedStream, E: FeedEventEmitter> {
     header: (),
     header_length: (),
     closed: bool,
+    remote: RemoteState,
+    pub(crate) requests: Requests,
+    verifier: Option<Verifier>,
+    storage: Option<Box<dyn FeedStorage>>,
+    local_info: Info,
+    remote_info: Info,
+    pending_acks: HashSet<u64>,
+    stats: FeedStats,
 
     pub(crate) _buffer: Option<Vec<Message>>,
 }
@@ -38,20 +82,21 @@ impl<FS: FeedStream, E: FeedEventEmitter> Debug for Feed<FS, E> {
             .field("header", &self.header)
             .field("header_length", &self.header_length)
             .field("closed", &self.closed)
+            .field("remote", &self.remote)
+            .field("requests", &self.requests)
+            .field("verifier", &self.verifier)
+            .field("storage", &self.storage)
+            .field("local_info", &self.local_info)
+            .field("remote_info", &self.remote_info)
+            .field("pending_acks", &self.pending_acks)
+            .field("stats", &self.stats)
             .field("_buffer", &self._buffer)
             .finish()
     }
 }
 
 impl<FS: FeedStream, E: FeedEventEmitter> Feed<FS, E> {
-    pub(crate) fn new<L: Into<Option<slog::Logger>>>(
-        logger: L,
-        stream: FS,
-        emitter: E,
-    ) -> Feed<FS, E> {
-        let log = logger
-            .into()
-            .unwrap_or_else(|| slog::Logger::root(slog_stdlog::StdLog.fuse(), o!()));
+    pub(crate) fn new(log: Log, stream: FS, emitter: E) -> Feed<FS, E> {
         Feed {
             log,
             key: None,
@@ -63,14 +108,271 @@ impl<FS: FeedStream, E: FeedEventEmitter> Feed<FS, E> {
             header: (),
             header_length: (),
             closed: false,
+            remote: RemoteState::new(),
+            requests: Requests::default(),
+            verifier: None,
+            storage: None,
+            local_info: Info::default(),
+            remote_info: Info::default(),
+            pending_acks: HashSet::new(),
+            stats: FeedStats::default(),
             _buffer: Some(Vec::new()),
         }
     }
 
     pub(crate) fn handshake(&mut self, handshake: schema::Handshake) {
-        slog::trace!(self.log, "Sending handshake: {:?}", handshake);
-        let bytes = write_msg(self.id.unwrap(), &Message::Handshake(handshake)).unwrap();
-        self.stream._push(&bytes);
+        trace!(self.log, "Sending handshake: {:?}", Payload(&handshake));
+        self._send(&Message::Handshake(handshake));
+    }
+
+    pub(crate) fn set_id(&mut self, id: Channel) {
+        self.log.record_channel("channel", id);
+        self.id = Some(id);
+    }
+
+    pub(crate) fn set_remote_id(&mut self, id: Channel) {
+        self.log.record_channel("remote_channel", id);
+        self.remote_id = Some(id);
+    }
+
+    pub(crate) fn stream(&self) -> &FS {
+        &self.stream
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.closed
+    }
+
+    pub fn info(&mut self, info: Info) -> bool {
+        self.local_info = info;
+        let mut message = schema::Info::new();
+        message.set_uploading(info.uploading);
+        message.set_downloading(info.downloading);
+        self._send(&Message::Info(message))
+    }
+
+    /// Sends an `Info` if uploading changed. Requests are not answered from the storage while
+    /// not uploading.
+    pub fn set_uploading(&mut self, uploading: bool) -> bool {
+        if self.local_info.uploading == uploading {
+            return !self.closed;
+        }
+        self.info(Info {
+            uploading,
+            ..self.local_info
+        })
+    }
+
+    /// Sends an `Info` if downloading changed.
+    pub fn set_downloading(&mut self, downloading: bool) -> bool {
+        if self.local_info.downloading == downloading {
+            return !self.closed;
+        }
+        self.info(Info {
+            downloading,
+            ..self.local_info
+        })
+    }
+
+    pub fn remote_uploading(&self) -> bool {
+        self.remote_info.uploading
+    }
+
+    pub fn remote_downloading(&self) -> bool {
+        self.remote_info.downloading
+    }
+
+    /// The last `Info` sent on this feed.
+    pub fn local_info(&self) -> Info {
+        self.local_info
+    }
+
+    /// The last `Info` received on this feed.
+    pub fn remote_info(&self) -> Info {
+        self.remote_info
+    }
+
+    /// Neither side is downloading anymore.
+    pub fn is_finished(&self) -> bool {
+        !self.local_info.downloading && !self.remote_info.downloading
+    }
+
+    pub fn have(&mut self, have: schema::Have) -> bool {
+        self._send(&Message::Have(have))
+    }
+
+    pub fn unhave(&mut self, unhave: schema::Unhave) -> bool {
+        self._send(&Message::Unhave(unhave))
+    }
+
+    pub fn want(&mut self, want: schema::Want) -> bool {
+        self._send(&Message::Want(want))
+    }
+
+    pub fn unwant(&mut self, unwant: schema::Unwant) -> bool {
+        self._send(&Message::Unwant(unwant))
+    }
+
+    /// Sends `request` and tracks it until the matching `Data` arrives, it is cancelled or it
+    /// times out. The `bool` is `false` if the caller should wait for a `FeedEvent::Drain`.
+    pub fn request(
+        &mut self,
+        request: schema::Request,
+        now: Instant,
+    ) -> Result<bool, RequestError> {
+        self.requests.add(request.clone(), now)?;
+        Ok(self._send(&Message::Request(request)))
+    }
+
+    /// Cancels the pending request for block `index`, returns `false` if there was none.
+    pub fn cancel(&mut self, index: u64) -> bool {
+        match self.requests.cancel(index) {
+            Some(request) => {
+                self._cancel(&request);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Cancels the requests that timed out by `now`, emitting `FeedEvent::RequestTimeout` for
+    /// each of them.
+    pub fn tick(&mut self, now: Instant) {
+        for request in self.requests.timed_out(now) {
+            self._cancel(&request);
+            self.emitter.emit(FeedEvent::RequestTimeout(request));
+        }
+    }
+
+    pub fn requests(&self) -> &Requests {
+        &self.requests
+    }
+
+    /// Verifies every incoming `Data` from now on. Data failing verification is not emitted,
+    /// there is a `FeedEvent::InvalidData` instead (and the request stays pending).
+    pub fn set_verifier(&mut self, verifier: Verifier) {
+        self.verifier = Some(verifier);
+    }
+
+    /// Stores verified `Data` into `storage` and answers `Request`s from it. Sets a verifier
+    /// for the storage key if there is none.
+    pub fn set_storage<S: FeedStorage + 'static>(
+        &mut self,
+        storage: S,
+    ) -> Result<(), StorageError> {
+        if self.verifier.is_none() {
+            let mut verifier = Verifier::new(storage.key().clone());
+            verifier.add_roots(storage.roots()?);
+            self.verifier = Some(verifier);
+        }
+        self.storage = Some(Box::new(storage));
+        Ok(())
+    }
+
+    /// Whether blocks can be appended, i.e. there is a storage with the secret key.
+    pub fn is_writable(&self) -> bool {
+        self.storage
+            .as_ref()
+            .is_some_and(|storage| storage.is_writable())
+    }
+
+    pub fn storage(&self) -> Option<&dyn FeedStorage> {
+        self.storage.as_deref()
+    }
+
+    /// Appends a block to the storage and announces it with a `Have`.
+    pub fn append(&mut self, value: &[u8]) -> Result<bool, StorageError> {
+        let storage = self.storage.as_mut().ok_or(StorageError::NotWritable)?;
+        let mut have = schema::Have::new();
+        have.set_start(storage.append(value)?);
+        Ok(self.have(have))
+    }
+
+    /// In ack mode the remote acknowledges the data, there is a `FeedEvent::Acked` then.
+    pub fn data(&mut self, data: schema::Data) -> bool {
+        if self.stream._ack() && !self.closed {
+            self.pending_acks.insert(data.get_index());
+        }
+        self._send(&Message::Data(data))
+    }
+
+    /// The number of blocks sent that the remote did not acknowledge yet.
+    pub fn pending_acks(&self) -> usize {
+        self.pending_acks.len()
+    }
+
+    pub fn stats(&self) -> FeedStats {
+        self.stats
+    }
+
+    /// What the remote announced to have and want on this feed.
+    pub fn remote(&self) -> &RemoteState {
+        &self.remote
+    }
+
+    pub fn remote_has(&self, index: u64) -> bool {
+        self.remote.remote_has(index)
+    }
+
+    /// Sends the payload of an extension, `false` if the remote does not support it.
+    pub fn extension(&mut self, name: &str, payload: &[u8]) -> bool {
+        match self.stream._extensions().id(name) {
+            Some(id) => self._send_extension(id, payload),
+            None => false,
+        }
+    }
+
+    /// Encodes `message` with the registered extension `X` and sends it.
+    pub fn send_extension<X: Extension>(&mut self, message: &X::Message) -> bool {
+        let (id, handler) = match self.stream._extensions().find::<X>() {
+            Some(found) => found,
+            None => return false,
+        };
+        let payload = match handler.borrow().as_any().downcast_ref::<X>() {
+            Some(extension) => extension.encode(message),
+            None => return false,
+        };
+        self._send_extension(id, &payload)
+    }
+
+    fn _send_extension(&mut self, id: usize, payload: &[u8]) -> bool {
+        if !self.stream._remote_supports(id) {
+            return false;
+        }
+        self._send(&wire_format::extension_message(id, payload))
+    }
+
+    fn _cancel(&mut self, request: &schema::Request) {
+        let mut cancel = schema::Cancel::new();
+        cancel.set_index(request.get_index());
+        if request.has_bytes() {
+            cancel.set_bytes(request.get_bytes());
+        }
+        if request.has_hash() {
+            cancel.set_hash(request.get_hash());
+        }
+        self._send(&Message::Cancel(cancel));
+    }
+
+    fn _send(&mut self, message: &Message) -> bool {
+        if self.closed {
+            return false;
+        }
+        let id = match self.id {
+            Some(id) => id,
+            None => return false,
+        };
+        let bytes = write_msg(id, message).unwrap();
+        self.stats.bytes_sent += wire_format::get_size(message) as u64;
+        self.stats.sent.add(message.r#type());
+        self.stream._push(&bytes)
+    }
+
+    pub(crate) fn _ondrain(&mut self) {
+        if self.closed {
+            return;
+        }
+        self.emitter.emit(FeedEvent::Drain);
     }
 
     pub(crate) fn _onclose(&mut self) {
@@ -79,7 +381,6 @@ impl<FS: FeedStream, E: FeedEventEmitter> Feed<FS, E> {
         }
         self.closed = true;
 
-        unimplemented!()
         //        if !self.stream.destroyed {
         //            self.close();
         //            if self.remote_id.is_some() {
@@ -95,11 +396,62 @@ impl<FS: FeedStream, E: FeedEventEmitter> Feed<FS, E> {
     }
 
     pub(crate) fn _resume(&mut self) {
-        unimplemented!()
+        if let Some(buffer) = self._buffer.take() {
+            for message in buffer {
+                self._emit(message);
+            }
+        }
     }
 
-    pub(crate) fn _onextension(&self, bytes: &[u8], start: usize, end: usize) {
-        unimplemented!()
+    pub(crate) fn _onextension(&mut self, bytes: &[u8], start: usize, end: usize) {
+        trace!(
+            self.log,
+            "_onextension({:?}, {}, {})",
+            Bytes(bytes),
+            start,
+            end
+        );
+        if self.closed {
+            return;
+        }
+        self.stats.bytes_received += (end - start) as u64;
+        self.stats.received.add(MessageType::Extension);
+
+        let (remote_id, length) = u64::decode_var(&bytes[start..end]);
+        if length == 0 {
+            return self.destroy("Remote sent an invalid extension message");
+        }
+        let id = match self.stream._local_extension(remote_id) {
+            Some(id) => id,
+            None => {
+                trace!(self.log, "Ignoring unsupported extension {}", remote_id);
+                return;
+            }
+        };
+        let payload = &bytes[start + length..end];
+
+        let extensions = self.stream._extensions();
+        let valid = match extensions.entry(id) {
+            // Its handler could not reply before the feed is opened locally
+            Some(Entry::Feed(_)) if self.id.is_none() => {
+                trace!(self.log, "Ignoring extension on an unopened feed");
+                true
+            }
+            Some(Entry::Feed(handler)) => handler.borrow_mut().on_payload(self, payload),
+            Some(Entry::Connection(handler)) => handler
+                .borrow_mut()
+                .on_payload(&mut Connection(&mut self.stream), payload),
+            _ => {
+                self.emitter.emit(FeedEvent::Extension(
+                    extensions.names()[id].clone(),
+                    payload.to_vec(),
+                ));
+                true
+            }
+        };
+        if !valid {
+            trace!(self.log, "Invalid {} message", extensions.names()[id]);
+        }
     }
 
     pub(crate) fn _onmessage(
@@ -113,7 +465,7 @@ impl<FS: FeedStream, E: FeedEventEmitter> Feed<FS, E> {
             self.log,
             "_onmessage({:?}, {:?}, {}, {})",
             r#type,
-            bytes,
+            Bytes(bytes),
             start,
             end
         );
@@ -123,14 +475,15 @@ impl<FS: FeedStream, E: FeedEventEmitter> Feed<FS, E> {
         if self.closed {
             return;
         }
+        self.stats.bytes_received += (end - start) as u64;
+        self.stats.received.add(r#type);
 
         if let Message::Handshake(ref handshake) = message {
             return self.stream._onhandshake(handshake);
         }
 
         if self._buffer.is_none() {
-            self.emitter.emit(FeedEvent::Message(message));
-            return;
+            return self._emit(message);
         }
 
         if self._buffer.as_ref().unwrap().len() > 16 {
@@ -140,8 +493,132 @@ impl<FS: FeedStream, E: FeedEventEmitter> Feed<FS, E> {
         self._buffer.as_mut().unwrap().push(message);
     }
 
+    fn _emit(&mut self, message: Message) {
+        match message {
+            Message::Info(ref info) => {
+                let previous = self.remote_info;
+                if info.has_uploading() {
+                    self.remote_info.uploading = info.get_uploading();
+                }
+                if info.has_downloading() {
+                    self.remote_info.downloading = info.get_downloading();
+                }
+                self.emitter.emit(FeedEvent::Message(message));
+                if self.remote_info.uploading != previous.uploading {
+                    self.emitter
+                        .emit(FeedEvent::RemoteUploading(self.remote_info.uploading));
+                }
+                if self.remote_info.downloading != previous.downloading {
+                    self.emitter
+                        .emit(FeedEvent::RemoteDownloading(self.remote_info.downloading));
+                }
+                return;
+            }
+            Message::Have(ref have) => {
+                if let Err(err) = self.remote.on_have(have) {
+                    return self.destroy(&err.to_string());
+                }
+                if have.get_ack() && self.pending_acks.remove(&have.get_start()) {
+                    self.emitter.emit(FeedEvent::Message(message.clone()));
+                    self.emitter.emit(FeedEvent::Acked(have.get_start()));
+                    return;
+                }
+            }
+            Message::Unhave(ref unhave) => self.remote.on_unhave(unhave),
+            Message::Want(ref want) => {
+                if let Err(err) = self.remote.on_want(want) {
+                    return self.destroy(&err.to_string());
+                }
+            }
+            Message::Unwant(ref unwant) => {
+                if let Err(err) = self.remote.on_unwant(unwant) {
+                    return self.destroy(&err.to_string());
+                }
+            }
+            Message::Request(ref request) => {
+                let data = match self.storage {
+                    Some(ref storage) if self.local_info.uploading => storage.data(request),
+                    _ => Ok(None),
+                };
+                match data {
+                    Ok(Some(data)) => {
+                        self.data(data);
+                    }
+                    Ok(None) => {}
+                    Err(err) => self.emitter.emit(FeedEvent::StorageError(err)),
+                }
+            }
+            Message::Data(ref data) => {
+                if let Some(ref mut verifier) = self.verifier {
+                    if let Err(err) = verifier.verify(data) {
+                        trace!(self.log, "Invalid data {}: {}", data.get_index(), err);
+                        self.emitter
+                            .emit(FeedEvent::InvalidData(data.get_index(), err));
+                        return;
+                    }
+                }
+                if let Some(ref mut storage) = self.storage {
+                    if let Err(err) = storage.put(data) {
+                        trace!(
+                            self.log,
+                            "Storing data {} failed: {}",
+                            data.get_index(),
+                            err
+                        );
+                        self.emitter.emit(FeedEvent::StorageError(err));
+                        return;
+                    }
+                }
+                if self.stream._ack() {
+                    let mut have = schema::Have::new();
+                    have.set_start(data.get_index());
+                    have.set_ack(true);
+                    self.have(have);
+                }
+                if let Some(request) = self.requests.on_data(data) {
+                    self.emitter.emit(FeedEvent::Message(message));
+                    self.emitter.emit(FeedEvent::RequestComplete(request));
+                    return;
+                }
+            }
+            _ => {}
+        }
+
+        self.emitter.emit(FeedEvent::Message(message));
+    }
+
     fn destroy(&mut self, err: &str) {
-        unimplemented!()
+        self.stream._destroy(err);
+    }
+}
+
+impl<FS: FeedStream, E: FeedEventEmitter> ExtensionFeed for Feed<FS, E> {
+    fn key(&self) -> Option<&Key> {
+        self.key.as_ref()
+    }
+
+    fn discovery_key(&self) -> Option<&DiscoveryKey> {
+        self.discovery_key.as_ref()
+    }
+
+    fn remote(&self) -> &RemoteState {
+        &self.remote
+    }
+
+    fn extension(&mut self, name: &str, payload: &[u8]) -> bool {
+        Feed::extension(self, name, payload)
+    }
+}
+
+/// The connection of a feed, handed to `ConnectionExtension` handlers.
+struct Connection<'a, FS: FeedStream>(&'a mut FS);
+
+impl<'a, FS: FeedStream> ExtensionConnection for Connection<'a, FS> {
+    fn extension(&mut self, name: &str, payload: &[u8]) -> bool {
+        match self.0._extensions().id(name) {
+            Some(id) => self.0._connection_extension(id, payload),
+            None => false,
+        }
     }
 }
 
@@ -150,6 +627,26 @@ pub enum FeedEvent {
     Handshake,
     // TODO not all message types will be emitted, and it should be reflected. (Handshake and Feed are not emitted, maybe others, too)
     Message(Message),
+    /// The outbound stream is below its high-water mark again, it is safe to send more.
+    Drain,
+    /// The `Data` answering a request arrived, it is emitted right before this event.
+    RequestComplete(schema::Request),
+    RequestTimeout(schema::Request),
+    /// The remote sent the block with this index, but it failed verification.
+    InvalidData(u64, VerifyError),
+    /// Reading from or writing to the feed storage failed.
+    StorageError(StorageError),
+    /// The remote acknowledged the `Data` with this index, emitted right after its `Have`.
+    Acked(u64),
+    /// The remote started or stopped uploading, emitted right after its `Info`.
+    RemoteUploading(bool),
+    /// The remote started or stopped downloading, emitted right after its `Info`.
+    RemoteDownloading(bool),
+    /// The name and payload of a message of an extension without a registered handler.
+    Extension(String, Vec<u8>),
+    /// Neither side is live and all feeds are finished, the protocol finalized itself. Also
+    /// emitted if it was finalized explicitly.
+    End,
 }
 pub trait FeedEventEmitter {
     fn emit(&mut self, event: FeedEvent);
@@ -162,13 +659,38 @@ mod tests {
 
     struct TestStream<'a>(&'a mut Vec<Vec<u8>>);
     impl<'a> FeedStream for TestStream<'a> {
-        fn _push(&mut self, bytes: &[u8]) {
+        fn _push(&mut self, bytes: &[u8]) -> bool {
             self.0.push(bytes.to_owned());
+            true
+        }
+
+        fn _ack(&self) -> bool {
+            false
+        }
+
+        fn _extensions(&self) -> Rc<Extensions> {
+            Rc::default()
+        }
+
+        fn _local_extension(&self, _remote_id: u64) -> Option<usize> {
+            None
+        }
+
+        fn _remote_supports(&self, _id: usize) -> bool {
+            false
+        }
+
+        fn _connection_extension(&mut self, _id: usize, _payload: &[u8]) -> bool {
+            false
         }
 
         fn _onhandshake(&mut self, handshake: &schema::Handshake) {
             unimplemented!()
         }
+
+        fn _destroy(&mut self, err: &str) {
+            unimplemented!()
+        }
     }
 
     struct TestEmitter<'a>(&'a mut Vec<FeedEvent>);
@@ -183,7 +705,7 @@ mod tests {
         let mut stream_bytes = Vec::new();
         let mut events = Vec::new();
         let mut feed = Feed::new(
-            None,
+            Log::new(None),
             TestStream(&mut stream_bytes),
             TestEmitter(&mut events),
         );
@@ -198,7 +720,7 @@ mod tests {
         assert_eq!(
             stream_bytes
                 .iter()
-                .map(|bytes| HEXLOWER.encode(&bytes))
+                .map(|bytes| HEXLOWER.encode(bytes))
                 .collect::<Vec<_>>(),
             vec!["14010a03666f6f10011a03626172220362617a2801"]
         );
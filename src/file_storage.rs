@@ -0,0 +1,366 @@
+use std::convert::TryInto;
+use std::fs::{self, File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use crate::bitfield::Bitfield;
+use crate::flat_tree;
+use crate::key_pair::KeyPair;
+use crate::merkle::Node;
+use crate::storage::{FeedStorage, StorageError};
+
+const HEADER_SIZE: u64 = 32;
+const BITFIELD_MAGIC: u32 = 0x0502_5700;
+const SIGNATURES_MAGIC: u32 = 0x0502_5701;
+const TREE_MAGIC: u32 = 0x0502_5702;
+const TREE_ENTRY_SIZE: u64 = 40;
+const SIGNATURE_SIZE: u64 = 64;
+// A bitfield page has 1024 bytes of data bits followed by the tree and index bits
+const BITFIELD_PAGE_SIZE: u64 = 3328;
+const BITFIELD_DATA_SIZE: u64 = 1024;
+
+/// Keeps a feed in a directory, using the hypercore on-disk layout: `key`, `secret_key`,
+/// `data`, `tree`, `signatures` and `bitfield` files.
+#[derive(Debug)]
+pub struct FileStorage {
+    dir: PathBuf,
+    key_pair: KeyPair,
+    data: File,
+    tree: File,
+    signatures: File,
+    bitfield_file: File,
+    bitfield: Bitfield,
+    length: u64,
+}
+
+impl FileStorage {
+    /// Opens an existing feed.
+    pub fn open<P: AsRef<Path>>(dir: P) -> Result<Self, StorageError> {
+        let dir = dir.as_ref().to_path_buf();
+        let key_pair = KeyPair::load(&dir)?;
+
+        let open = |name: &str| {
+            OpenOptions::new()
+                .read(true)
+                .write(true)
+                .open(dir.join(name))
+        };
+        let mut data = open("data")?;
+        let mut tree = open("tree")?;
+        let mut signatures = open("signatures")?;
+        let mut bitfield_file = open("bitfield")?;
+        check_header(&mut tree, TREE_MAGIC, TREE_ENTRY_SIZE)?;
+        check_header(&mut signatures, SIGNATURES_MAGIC, SIGNATURE_SIZE)?;
+        check_header(&mut bitfield_file, BITFIELD_MAGIC, BITFIELD_PAGE_SIZE)?;
+        data.seek(SeekFrom::Start(0))?;
+
+        let mut pages = Vec::new();
+        bitfield_file.read_to_end(&mut pages)?;
+        let bitfield = Bitfield::from_bytes(
+            pages
+                .chunks(BITFIELD_PAGE_SIZE as usize)
+                .flat_map(|page| &page[..page.len().min(BITFIELD_DATA_SIZE as usize)])
+                .cloned()
+                .collect(),
+        );
+        let length = signatures.metadata()?.len().saturating_sub(HEADER_SIZE) / SIGNATURE_SIZE;
+
+        Ok(FileStorage {
+            dir,
+            key_pair,
+            data,
+            tree,
+            signatures,
+            bitfield_file,
+            bitfield,
+            length,
+        })
+    }
+
+    /// Creates an empty feed, writable if the secret key is given. Fails if the directory
+    /// already has a feed.
+    pub fn create<P: AsRef<Path>>(dir: P, key_pair: &KeyPair) -> Result<Self, StorageError> {
+        let dir = dir.as_ref();
+        fs::create_dir_all(dir)?;
+        let create = |name: &str, contents: &[u8]| {
+            OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .open(dir.join(name))?
+                .write_all(contents)
+        };
+        create("data", &[])?;
+        create("tree", &header(TREE_MAGIC, TREE_ENTRY_SIZE, "BLAKE2b"))?;
+        create(
+            "signatures",
+            &header(SIGNATURES_MAGIC, SIGNATURE_SIZE, "Ed25519"),
+        )?;
+        create("bitfield", &header(BITFIELD_MAGIC, BITFIELD_PAGE_SIZE, ""))?;
+        key_pair.save(dir)?;
+        FileStorage::open(dir)
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Blocks are stored back to back, the offset is the size of all the blocks before.
+    fn offset(&self, index: u64) -> Result<u64, StorageError> {
+        let mut offset = 0;
+        for root in flat_tree::full_roots(2 * index) {
+            offset += self
+                .node(root)?
+                .ok_or(StorageError::MissingNode(root))?
+                .size;
+        }
+        Ok(offset)
+    }
+}
+
+fn header(magic: u32, entry_size: u64, algorithm: &str) -> [u8; HEADER_SIZE as usize] {
+    let mut header = [0; HEADER_SIZE as usize];
+    header[..4].copy_from_slice(&magic.to_be_bytes());
+    header[5..7].copy_from_slice(&(entry_size as u16).to_be_bytes());
+    header[7] = algorithm.len() as u8;
+    header[8..8 + algorithm.len()].copy_from_slice(algorithm.as_bytes());
+    header
+}
+
+fn check_header(file: &mut File, magic: u32, entry_size: u64) -> Result<(), StorageError> {
+    let mut header = [0; HEADER_SIZE as usize];
+    file.read_exact(&mut header)
+        .map_err(|_| StorageError::InvalidHeader)?;
+    if header[..4] != magic.to_be_bytes()
+        || header[4] != 0
+        || header[5..7] != (entry_size as u16).to_be_bytes()
+    {
+        return Err(StorageError::InvalidHeader);
+    }
+    Ok(())
+}
+
+/// Reads `buf.len()` bytes at `offset`, false if the file is shorter or the entry was never
+/// written.
+fn read_entry(mut file: &File, offset: u64, buf: &mut [u8]) -> Result<bool, StorageError> {
+    if file.metadata()?.len() < offset + buf.len() as u64 {
+        return Ok(false);
+    }
+    file.seek(SeekFrom::Start(offset))?;
+    file.read_exact(buf)?;
+    Ok(buf.iter().any(|b| *b != 0))
+}
+
+fn write_entry(mut file: &File, offset: u64, buf: &[u8]) -> Result<(), StorageError> {
+    file.seek(SeekFrom::Start(offset))?;
+    file.write_all(buf)?;
+    Ok(())
+}
+
+impl FeedStorage for FileStorage {
+    fn key_pair(&self) -> &KeyPair {
+        &self.key_pair
+    }
+
+    fn len(&self) -> u64 {
+        self.length
+    }
+
+    fn bitfield(&self) -> &Bitfield {
+        &self.bitfield
+    }
+
+    fn block(&self, index: u64) -> Result<Option<Vec<u8>>, StorageError> {
+        if !self.has(index) {
+            return Ok(None);
+        }
+        let size = match self.node(2 * index)? {
+            Some(leaf) => leaf.size,
+            None => return Ok(None),
+        };
+        let mut value = vec![0; size as usize];
+        let offset = self.offset(index)?;
+        (&self.data).seek(SeekFrom::Start(offset))?;
+        (&self.data).read_exact(&mut value)?;
+        Ok(Some(value))
+    }
+
+    fn node(&self, index: u64) -> Result<Option<Node>, StorageError> {
+        let mut entry = [0; TREE_ENTRY_SIZE as usize];
+        if !read_entry(
+            &self.tree,
+            HEADER_SIZE + index * TREE_ENTRY_SIZE,
+            &mut entry,
+        )? {
+            return Ok(None);
+        }
+        let mut hash = [0; 32];
+        hash.copy_from_slice(&entry[..32]);
+        let size = u64::from_be_bytes(entry[32..].try_into().unwrap());
+        Ok(Some(Node { index, hash, size }))
+    }
+
+    fn signature(&self, index: u64) -> Result<Option<Vec<u8>>, StorageError> {
+        let mut signature = vec![0; SIGNATURE_SIZE as usize];
+        if !read_entry(
+            &self.signatures,
+            HEADER_SIZE + index * SIGNATURE_SIZE,
+            &mut signature,
+        )? {
+            return Ok(None);
+        }
+        Ok(Some(signature))
+    }
+
+    fn write_block(&mut self, index: u64, value: &[u8]) -> Result<(), StorageError> {
+        // The offset is only known with the leaf and the roots left of it, so they go first
+        self.write_node(&Node::leaf(index, value))?;
+        let offset = self.offset(index)?;
+        write_entry(&self.data, offset, value)?;
+
+        self.bitfield.set(index, true);
+        let byte = index / 8;
+        let page = byte / BITFIELD_DATA_SIZE;
+        write_entry(
+            &self.bitfield_file,
+            HEADER_SIZE + page * BITFIELD_PAGE_SIZE + byte % BITFIELD_DATA_SIZE,
+            &self.bitfield.as_bytes()[byte as usize..byte as usize + 1],
+        )?;
+        // Pages are always complete on disk
+        let end = HEADER_SIZE + (page + 1) * BITFIELD_PAGE_SIZE;
+        if self.bitfield_file.metadata()?.len() < end {
+            self.bitfield_file.set_len(end)?;
+        }
+        Ok(())
+    }
+
+    fn write_node(&mut self, node: &Node) -> Result<(), StorageError> {
+        let mut entry = [0; TREE_ENTRY_SIZE as usize];
+        entry[..32].copy_from_slice(&node.hash);
+        entry[32..].copy_from_slice(&node.size.to_be_bytes());
+        write_entry(
+            &self.tree,
+            HEADER_SIZE + node.index * TREE_ENTRY_SIZE,
+            &entry,
+        )
+    }
+
+    fn write_signature(&mut self, index: u64, signature: &[u8]) -> Result<(), StorageError> {
+        write_entry(
+            &self.signatures,
+            HEADER_SIZE + index * SIGNATURE_SIZE,
+            signature,
+        )?;
+        self.length = self.length.max(index + 1);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::io;
+    use std::process;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::*;
+    use crate::merkle::Verifier;
+    use crate::schema;
+    use crate::storage::MemoryStorage;
+
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new() -> Self {
+            static COUNTER: AtomicUsize = AtomicUsize::new(0);
+            TempDir(env::temp_dir().join(format!(
+                "hypercore-protocol-{}-{}",
+                process::id(),
+                COUNTER.fetch_add(1, Ordering::SeqCst)
+            )))
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn request(index: u64) -> schema::Request {
+        let mut request = schema::Request::new();
+        request.set_index(index);
+        request
+    }
+
+    const VALUES: [&[u8]; 5] = [b"foo", b"bar", b"baz", b"qux", b"quux"];
+
+    #[test]
+    fn test_append_and_reopen() {
+        sodiumoxide::init().unwrap();
+        let key_pair = KeyPair::generate();
+        let dir = TempDir::new();
+
+        let mut memory = MemoryStorage::new(key_pair.clone());
+        let mut storage = FileStorage::create(&dir.0, &key_pair).unwrap();
+        for value in &VALUES {
+            memory.append(value).unwrap();
+            storage.append(value).unwrap();
+        }
+        drop(storage);
+
+        let storage = FileStorage::open(&dir.0).unwrap();
+        assert!(storage.is_writable());
+        assert_eq!(storage.len(), 5);
+        assert_eq!(storage.roots(), memory.roots());
+        assert_eq!(storage.signature(4), memory.signature(4));
+        for (index, value) in VALUES.iter().enumerate() {
+            assert_eq!(storage.block(index as u64), Ok(Some(value.to_vec())));
+        }
+
+        assert_eq!(fs::read(dir.0.join("data")).unwrap(), VALUES.concat());
+        let tree = fs::read(dir.0.join("tree")).unwrap();
+        assert_eq!(&tree[..12], b"\x05\x02\x57\x02\x00\x00\x28\x07BLAK");
+        assert_eq!(tree.len() as u64, HEADER_SIZE + 9 * TREE_ENTRY_SIZE);
+        let bitfield = fs::read(dir.0.join("bitfield")).unwrap();
+        assert_eq!(bitfield.len() as u64, HEADER_SIZE + BITFIELD_PAGE_SIZE);
+        assert_eq!(bitfield[HEADER_SIZE as usize], 0b1111_1000);
+
+        assert!(matches!(
+            FileStorage::create(&dir.0, &KeyPair::generate()),
+            Err(StorageError::Io(io::ErrorKind::AlreadyExists, _))
+        ));
+        let storage = FileStorage::open(&dir.0).unwrap();
+        assert_eq!(storage.key_pair(), &key_pair);
+        assert_eq!(storage.len(), 5);
+    }
+
+    #[test]
+    fn test_replicate() {
+        sodiumoxide::init().unwrap();
+        let key_pair = KeyPair::generate();
+        let key = key_pair.key().clone();
+        let dir = TempDir::new();
+
+        let mut writer = MemoryStorage::new(key_pair);
+        for value in &VALUES {
+            writer.append(value).unwrap();
+        }
+
+        let mut verifier = Verifier::new(key.clone());
+        let mut replica = FileStorage::create(&dir.0, &key.clone().into()).unwrap();
+        for index in &[3, 0, 4] {
+            let data = writer.data(&request(*index)).unwrap().unwrap();
+            verifier.verify(&data).unwrap();
+            replica.put(&data).unwrap();
+        }
+        drop(replica);
+
+        let replica = FileStorage::open(&dir.0).unwrap();
+        assert!(!replica.is_writable());
+        assert_eq!(replica.len(), 5);
+        assert_eq!(replica.roots(), writer.roots());
+        assert_eq!(replica.block(3), Ok(Some(b"qux".to_vec())));
+        assert_eq!(replica.block(1), Ok(None));
+        assert_eq!(replica.data(&request(0)), writer.data(&request(0)));
+    }
+}
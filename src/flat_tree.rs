@@ -0,0 +1,247 @@
+// Index math of flat trees, see https://github.com/mafintosh/flat-tree
+//
+// A flat tree lays out a binary tree in a list: leaves are on the even indices, and every
+// parent is between its children.
+//
+//      3
+//    /   \
+//   1     5
+//  / \   / \
+// 0   2 4   6
+
+/// The index of the node at `depth` (0 for leaves), being the `offset`th node on its level.
+pub fn index(depth: u64, offset: u64) -> u64 {
+    (offset << (depth + 1)) | ((1 << depth) - 1)
+}
+
+pub fn depth(index: u64) -> u64 {
+    u64::from((!index).trailing_zeros())
+}
+
+pub fn offset(index: u64) -> u64 {
+    index >> (depth(index) + 1)
+}
+
+pub fn parent(i: u64) -> u64 {
+    index(depth(i) + 1, offset(i) >> 1)
+}
+
+pub fn sibling(i: u64) -> u64 {
+    index(depth(i), offset(i) ^ 1)
+}
+
+/// The left and right child, leaves have none.
+pub fn children(i: u64) -> Option<(u64, u64)> {
+    let depth = depth(i);
+    if depth == 0 {
+        return None;
+    }
+    let offset = offset(i) * 2;
+    Some((index(depth - 1, offset), index(depth - 1, offset + 1)))
+}
+
+/// The leftmost leaf below `i`.
+pub fn left_span(i: u64) -> u64 {
+    i + 1 - (1 << depth(i))
+}
+
+/// The rightmost leaf below `i`.
+pub fn right_span(i: u64) -> u64 {
+    i + (1 << depth(i)) - 1
+}
+
+pub fn spans(i: u64) -> (u64, u64) {
+    (left_span(i), right_span(i))
+}
+
+/// The roots of the tree whose leaves are left of `i`, i.e. of a feed of `i / 2` blocks.
+pub fn full_roots(i: u64) -> Vec<u64> {
+    assert_eq!(i & 1, 0, "You can only look up roots for depth(0) blocks");
+    let mut roots = Vec::new();
+    let mut remaining = i / 2;
+    let mut offset = 0;
+    while remaining > 0 {
+        let mut factor = 1;
+        while factor * 2 <= remaining {
+            factor *= 2;
+        }
+        roots.push(offset + factor - 1);
+        offset += 2 * factor;
+        remaining -= factor;
+    }
+    roots
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Proof {
+    /// The nodes to include in the `Data` reply, ordered from the block up
+    pub nodes: Vec<u64>,
+    /// Whether the proof reaches up to the roots, so the reply has to carry their signature
+    pub needs_signature: bool,
+}
+
+/// The nodes proving block `index` of a feed of `length` blocks, to a remote that already has
+/// the nodes `remote_has` returns `true` for (e.g. the roots it verified before).
+pub fn proof<F: Fn(u64) -> bool>(index: u64, length: u64, remote_has: F) -> Proof {
+    assert!(index < length, "Block {} is not in the tree", index);
+    let mut nodes = Vec::new();
+    let mut next = 2 * index;
+    while !remote_has(next) {
+        let sibling = sibling(next);
+        if right_span(sibling) >= 2 * length {
+            // `next` is a root, the others are needed to check the signature
+            nodes.extend(
+                full_roots(2 * length)
+                    .into_iter()
+                    .filter(|root| *root != next && !remote_has(*root)),
+            );
+            return Proof {
+                nodes,
+                needs_signature: true,
+            };
+        }
+        if !remote_has(sibling) {
+            nodes.push(sibling);
+        }
+        next = parent(next);
+    }
+    Proof {
+        nodes,
+        needs_signature: false,
+    }
+}
+
+/// The nodes a remote has according to the `nodes` digest of its `Request` for block `index`,
+/// as built by hypercore's `TreeIndex.digest`. Bit 0 tells that the remote has a verified root
+/// on the path, each bit above that whether it has the sibling at the next level up.
+pub fn remote_nodes(index: u64, digest: u64, length: u64) -> Vec<u64> {
+    let mut next = 2 * index;
+    if digest == 1 {
+        return vec![next];
+    }
+
+    let mut nodes = Vec::new();
+    let has_root = digest & 1 != 0;
+    let mut digest = digest >> 1;
+    // Nodes past the end of the tree cannot be needed in a proof
+    while digest != 0 && right_span(next) < 2 * length {
+        if digest == 1 && has_root {
+            let root = next;
+            nodes.push(root);
+            if sibling(next) < next {
+                next = sibling(next);
+            }
+            nodes.extend(
+                full_roots(right_span(next) + 2)
+                    .into_iter()
+                    .filter(|&node| node != root),
+            );
+            break;
+        }
+        if digest & 1 != 0 {
+            nodes.push(sibling(next));
+        }
+        next = parent(next);
+        digest >>= 1;
+    }
+    nodes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_index() {
+        assert_eq!(index(0, 0), 0);
+        assert_eq!(index(0, 1), 2);
+        assert_eq!(index(1, 0), 1);
+        assert_eq!(index(1, 1), 5);
+        assert_eq!(index(2, 0), 3);
+        assert_eq!((0..5).map(depth).collect::<Vec<_>>(), vec![0, 1, 0, 2, 0]);
+        assert_eq!((0..5).map(offset).collect::<Vec<_>>(), vec![0, 0, 1, 0, 2]);
+    }
+
+    #[test]
+    fn test_relatives() {
+        assert_eq!(parent(0), 1);
+        assert_eq!(parent(2), 1);
+        assert_eq!(parent(1), 3);
+        assert_eq!(parent(5), 3);
+        assert_eq!(sibling(0), 2);
+        assert_eq!(sibling(2), 0);
+        assert_eq!(sibling(1), 5);
+        assert_eq!(sibling(5), 1);
+        assert_eq!(children(0), None);
+        assert_eq!(children(1), Some((0, 2)));
+        assert_eq!(children(3), Some((1, 5)));
+    }
+
+    #[test]
+    fn test_spans() {
+        assert_eq!(spans(0), (0, 0));
+        assert_eq!(spans(1), (0, 2));
+        assert_eq!(spans(3), (0, 6));
+        assert_eq!(spans(23), (16, 30));
+        assert_eq!(spans(27), (24, 30));
+    }
+
+    #[test]
+    fn test_full_roots() {
+        assert_eq!(full_roots(0), Vec::<u64>::new());
+        assert_eq!(full_roots(2), vec![0]);
+        assert_eq!(full_roots(6), vec![1, 4]);
+        assert_eq!(full_roots(8), vec![3]);
+        assert_eq!(full_roots(16), vec![7]);
+        assert_eq!(full_roots(18), vec![7, 16]);
+        assert_eq!(full_roots(20), vec![7, 17]);
+        assert_eq!(full_roots(22), vec![7, 17, 20]);
+    }
+
+    #[test]
+    fn test_proof() {
+        assert_eq!(
+            proof(0, 4, |_| false),
+            Proof {
+                nodes: vec![2, 5],
+                needs_signature: true,
+            }
+        );
+        assert_eq!(
+            proof(0, 4, |i| i == 3),
+            Proof {
+                nodes: vec![2, 5],
+                needs_signature: false,
+            }
+        );
+        assert_eq!(
+            proof(0, 3, |_| false),
+            Proof {
+                nodes: vec![2, 4],
+                needs_signature: true,
+            }
+        );
+        assert_eq!(
+            proof(2, 3, |i| i == 1),
+            Proof {
+                nodes: vec![],
+                needs_signature: true,
+            }
+        );
+    }
+
+    #[test]
+    fn test_remote_nodes() {
+        assert_eq!(remote_nodes(0, 0, 4), Vec::<u64>::new());
+        assert_eq!(remote_nodes(0, 1, 4), vec![0]);
+        // The sibling of leaf 0, and the sibling of its parent
+        assert_eq!(remote_nodes(0, 0b110, 4), vec![2, 5]);
+        // The sibling of leaf 0, and the verified root above
+        assert_eq!(remote_nodes(0, 0b111, 4), vec![2, 1]);
+        // The verified node is a right child, the roots to its left are needed too
+        assert_eq!(remote_nodes(2, 0b111, 4), vec![6, 5, 1]);
+        // Stops at the top of the tree of 1 block
+        assert_eq!(remote_nodes(0, 0b1110, 1), vec![2]);
+        assert_eq!(remote_nodes(2, u64::MAX - 1, 4), vec![6, 1, 11]);
+    }
+}
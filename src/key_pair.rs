@@ -0,0 +1,180 @@
+use std::convert::TryFrom;
+use std::fs::{self, OpenOptions};
+use std::io::{self, ErrorKind, Write};
+#[cfg(unix)]
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+
+use sodiumoxide::crypto::sign;
+
+use crate::merkle::{tree_hash, Node};
+use crate::protocol::Key;
+
+/// The ed25519 keys of a feed. Without the secret key the feed is only readable, with it the
+/// roots of the Merkle tree can be signed, so blocks can be appended.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct KeyPair {
+    public: Key,
+    secret: Option<sign::SecretKey>,
+}
+
+impl KeyPair {
+    pub fn generate() -> Self {
+        let (public, secret) = sign::gen_keypair();
+        KeyPair {
+            public: Key(public.0),
+            secret: Some(secret),
+        }
+    }
+
+    pub fn public(key: Key) -> Self {
+        KeyPair {
+            public: key,
+            secret: None,
+        }
+    }
+
+    pub fn from_secret_key(secret: sign::SecretKey) -> Self {
+        KeyPair {
+            public: Key(secret.public_key().0),
+            secret: Some(secret),
+        }
+    }
+
+    pub fn key(&self) -> &Key {
+        &self.public
+    }
+
+    pub fn secret_key(&self) -> Option<&sign::SecretKey> {
+        self.secret.as_ref()
+    }
+
+    pub fn is_writable(&self) -> bool {
+        self.secret.is_some()
+    }
+
+    /// `None` if the secret key is missing.
+    pub fn sign_roots(&self, roots: &[Node]) -> Option<Vec<u8>> {
+        let secret = self.secret.as_ref()?;
+        Some(
+            sign::sign_detached(&tree_hash(roots), secret)
+                .as_ref()
+                .to_vec(),
+        )
+    }
+
+    pub fn verify_roots(&self, roots: &[Node], signature: &[u8]) -> bool {
+        match sign::Signature::try_from(signature) {
+            Ok(signature) => sign::verify_detached(
+                &signature,
+                &tree_hash(roots),
+                &sign::PublicKey(self.public.0),
+            ),
+            Err(_) => false,
+        }
+    }
+
+    /// Loads the `key` and, if it exists, the `secret_key` file of a hypercore directory.
+    pub fn load<P: AsRef<Path>>(dir: P) -> io::Result<Self> {
+        let dir = dir.as_ref();
+        let public = fs::read(dir.join("key"))?;
+        let public = <[u8; 32]>::try_from(public.as_slice())
+            .map_err(|_| io::Error::new(ErrorKind::InvalidData, "Invalid key"))?;
+        let secret = match fs::read(dir.join("secret_key")) {
+            Ok(secret) => Some(
+                sign::SecretKey::from_slice(&secret)
+                    .filter(|secret| secret.public_key().0 == public)
+                    .ok_or_else(|| io::Error::new(ErrorKind::InvalidData, "Invalid secret key"))?,
+            ),
+            Err(ref err) if err.kind() == ErrorKind::NotFound => None,
+            Err(err) => return Err(err),
+        };
+        Ok(KeyPair {
+            public: Key(public),
+            secret,
+        })
+    }
+
+    /// Writes the `key` and, if writable, the `secret_key` file into a hypercore directory. On
+    /// unix a new `secret_key` is only readable by its owner.
+    pub fn save<P: AsRef<Path>>(&self, dir: P) -> io::Result<()> {
+        let dir = dir.as_ref();
+        fs::create_dir_all(dir)?;
+        fs::write(dir.join("key"), self.public.0)?;
+        if let Some(ref secret) = self.secret {
+            let mut options = OpenOptions::new();
+            options.write(true).create(true).truncate(true);
+            #[cfg(unix)]
+            options.mode(0o600);
+            options
+                .open(dir.join("secret_key"))?
+                .write_all(&secret.0[..])?;
+        }
+        Ok(())
+    }
+}
+
+impl From<Key> for KeyPair {
+    fn from(key: Key) -> Self {
+        KeyPair::public(key)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::process;
+
+    use super::*;
+
+    #[test]
+    fn test_sign_and_verify() {
+        sodiumoxide::init().unwrap();
+        let key_pair = KeyPair::generate();
+        let roots = [Node::leaf(0, b"foo")];
+
+        let signature = key_pair.sign_roots(&roots).unwrap();
+        let readable = KeyPair::public(key_pair.key().clone());
+        assert!(!readable.is_writable());
+        assert_eq!(readable.sign_roots(&roots), None);
+        assert!(readable.verify_roots(&roots, &signature));
+        assert!(!readable.verify_roots(&[Node::leaf(0, b"bar")], &signature));
+        assert!(!readable.verify_roots(&roots, &signature[1..]));
+
+        let secret_key = key_pair.secret_key().unwrap().clone();
+        assert_eq!(KeyPair::from_secret_key(secret_key), key_pair);
+    }
+
+    #[test]
+    fn test_save_and_load() {
+        sodiumoxide::init().unwrap();
+        let dir = env::temp_dir().join(format!("hypercore-protocol-keys-{}", process::id()));
+        let key_pair = KeyPair::generate();
+
+        key_pair.save(&dir).unwrap();
+        assert_eq!(KeyPair::load(&dir).unwrap(), key_pair);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let metadata = fs::metadata(dir.join("secret_key")).unwrap();
+            assert_eq!(metadata.permissions().mode() & 0o777, 0o600);
+        }
+
+        fs::remove_file(dir.join("secret_key")).unwrap();
+        assert_eq!(
+            KeyPair::load(&dir).unwrap(),
+            KeyPair::public(key_pair.key().clone())
+        );
+
+        fs::write(
+            dir.join("secret_key"),
+            &KeyPair::generate().secret.unwrap().0[..],
+        )
+        .unwrap();
+        assert_eq!(
+            KeyPair::load(&dir).unwrap_err().kind(),
+            ErrorKind::InvalidData
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
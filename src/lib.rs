@@ -1,14 +1,27 @@
 // TODO integer_encoding crate simply truncates when casting u64 to e.g. u16. It should
 //  report an error instead.
 
+pub mod bitfield;
+pub mod bitfield_rle;
 mod crypto_stream;
+pub mod extension;
 mod feed;
+pub mod file_storage;
+pub mod flat_tree;
+pub mod key_pair;
+mod logging;
+pub mod merkle;
 pub mod protocol;
+pub mod remote_info;
+pub mod remote_state;
+pub mod requests;
+pub mod stats;
+pub mod storage;
 mod wire_format;
 
 #[cfg(test)]
 mod tests;
 
-pub use feed::{FeedEvent, FeedEventEmitter};
+pub use feed::{FeedEvent, FeedEventEmitter, Info};
 
 include!(concat!(env!("OUT_DIR"), "/protos/mod.rs"));
@@ -0,0 +1,142 @@
+use std::fmt::{self, Debug, Formatter};
+
+use data_encoding::HEXLOWER;
+#[cfg(not(feature = "tracing"))]
+use slog::Drain;
+use slog::{o, Logger};
+
+use crate::protocol::{Channel, DiscoveryKey};
+
+/// Where `Protocol` and `Feed` log to.
+///
+/// Goes to the `slog::Logger` given to `ProtocolBuilder::logger`. Without one, it goes to the
+/// `log` crate, or to `tracing` with the `tracing` feature. With the `tracing` feature every
+/// connection has a `protocol` span and every feed a `feed` span in it, with `channel`,
+/// `remote_channel` and `discovery_key` fields.
+#[derive(Clone)]
+pub(crate) struct Log {
+    logger: Option<Logger>,
+    #[cfg(feature = "tracing")]
+    span: tracing::Span,
+}
+
+impl Log {
+    pub(crate) fn new(logger: Option<Logger>) -> Self {
+        #[cfg(not(feature = "tracing"))]
+        let logger = logger.or_else(|| Some(Logger::root(slog_stdlog::StdLog.fuse(), o!())));
+        Log {
+            logger,
+            #[cfg(feature = "tracing")]
+            span: tracing::debug_span!("protocol"),
+        }
+    }
+
+    /// The log of a feed of this connection.
+    pub(crate) fn feed(&self, discovery_key: &DiscoveryKey) -> Self {
+        let discovery_key = HEXLOWER.encode(discovery_key.as_bytes());
+        Log {
+            logger: self
+                .logger
+                .as_ref()
+                .map(|logger| logger.new(o!("discovery_key" => discovery_key.clone()))),
+            #[cfg(feature = "tracing")]
+            span: tracing::debug_span!(
+                parent: &self.span,
+                "feed",
+                channel = tracing::field::Empty,
+                remote_channel = tracing::field::Empty,
+                discovery_key = discovery_key.as_str()
+            ),
+        }
+    }
+
+    /// Fills the `channel` or `remote_channel` field of a feed span.
+    pub(crate) fn record_channel(&self, _field: &'static str, _channel: Channel) {
+        #[cfg(feature = "tracing")]
+        self.span.record(_field, _channel.0);
+    }
+
+    pub(crate) fn logger(&self) -> Option<&Logger> {
+        self.logger.as_ref()
+    }
+
+    #[cfg(feature = "tracing")]
+    pub(crate) fn span(&self) -> &tracing::Span {
+        &self.span
+    }
+}
+
+impl Debug for Log {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        f.debug_struct("Log").finish()
+    }
+}
+
+/// `slog::trace!` on a `Log`, also emitted in its span with the `tracing` feature.
+macro_rules! trace {
+    ($log:expr, $($args:tt)+) => {{
+        let log: &$crate::logging::Log = &$log;
+        if let Some(logger) = log.logger() {
+            slog::trace!(logger, $($args)+);
+        }
+        #[cfg(feature = "tracing")]
+        log.span().in_scope(|| tracing::trace!($($args)+));
+    }};
+}
+
+/// A trace outside of a connection, to the `log` crate or `tracing` with the `tracing` feature.
+macro_rules! global_trace {
+    ($($args:tt)+) => {{
+        #[cfg(not(feature = "tracing"))]
+        log::trace!($($args)+);
+        #[cfg(feature = "tracing")]
+        tracing::trace!($($args)+);
+    }};
+}
+
+pub(crate) use global_trace;
+pub(crate) use trace;
+
+/// Logs a byte buffer, which might be a decrypted payload. Only its length is printed unless
+/// the `log-payloads` feature is on.
+pub(crate) struct Bytes<'a>(pub(crate) &'a [u8]);
+
+impl Debug for Bytes<'_> {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        if cfg!(feature = "log-payloads") {
+            Debug::fmt(self.0, f)
+        } else {
+            write!(f, "<{} bytes>", self.0.len())
+        }
+    }
+}
+
+/// Logs a message, which might carry user data or blocks. Only printed with the
+/// `log-payloads` feature.
+pub(crate) struct Payload<'a, T: Debug>(pub(crate) &'a T);
+
+impl<T: Debug> Debug for Payload<'_, T> {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        if cfg!(feature = "log-payloads") {
+            Debug::fmt(self.0, f)
+        } else {
+            write!(f, "<payload>")
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bytes() {
+        let bytes = [1u8, 2, 3];
+        let expected = if cfg!(feature = "log-payloads") {
+            "[1, 2, 3]"
+        } else {
+            "<3 bytes>"
+        };
+        assert_eq!(format!("{:?}", Bytes(&bytes)), expected);
+    }
+}
@@ -0,0 +1,313 @@
+// Merkle tree hashing of hypercore, see https://github.com/mafintosh/hypercore-crypto
+//
+// Nodes are addressed by their flat-tree index (https://github.com/mafintosh/flat-tree), block
+// `i` is leaf `2 * i`.
+
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::fmt::{self, Display, Formatter};
+
+use sodiumoxide::crypto::generichash;
+
+use crate::flat_tree::{full_roots, parent, right_span, sibling};
+use crate::key_pair::KeyPair;
+use crate::protocol::Key;
+use crate::remote_state::MAX_BLOCKS;
+use crate::schema;
+
+const LEAF_TYPE: u8 = 0;
+const PARENT_TYPE: u8 = 1;
+const ROOT_TYPE: u8 = 2;
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Node {
+    pub index: u64,
+    pub hash: [u8; 32],
+    /// Number of bytes in the blocks below this node
+    pub size: u64,
+}
+
+impl Node {
+    /// The leaf of block `index` holding `data`.
+    pub fn leaf(index: u64, data: &[u8]) -> Node {
+        Node {
+            index: 2 * index,
+            hash: hash(&[&[LEAF_TYPE], &(data.len() as u64).to_be_bytes(), data]),
+            size: data.len() as u64,
+        }
+    }
+
+    pub fn parent(a: &Node, b: &Node) -> Node {
+        let (left, right) = if a.index < b.index { (a, b) } else { (b, a) };
+        let size = left.size + right.size;
+        Node {
+            index: parent(left.index),
+            hash: hash(&[&[PARENT_TYPE], &size.to_be_bytes(), &left.hash, &right.hash]),
+            size,
+        }
+    }
+}
+
+impl TryFrom<&schema::Data_Node> for Node {
+    type Error = ();
+
+    fn try_from(node: &schema::Data_Node) -> Result<Self, Self::Error> {
+        let mut hash = [0u8; 32];
+        if node.get_hash().len() != hash.len() {
+            Err(())
+        } else {
+            hash.copy_from_slice(node.get_hash());
+            Ok(Node {
+                index: node.get_index(),
+                hash,
+                size: node.get_size(),
+            })
+        }
+    }
+}
+
+/// The hash of a whole tree, given its roots ordered by index. This is what gets signed.
+pub fn tree_hash(roots: &[Node]) -> [u8; 32] {
+    let mut parts: Vec<&[u8]> = vec![&[ROOT_TYPE]];
+    let encoded = roots
+        .iter()
+        .map(|root| (root.index.to_be_bytes(), root.size.to_be_bytes()))
+        .collect::<Vec<_>>();
+    for (root, (index, size)) in roots.iter().zip(encoded.iter()) {
+        parts.push(&root.hash);
+        parts.push(index);
+        parts.push(size);
+    }
+    hash(&parts)
+}
+
+fn hash(parts: &[&[u8]]) -> [u8; 32] {
+    let mut hasher = generichash::State::new(32, None).unwrap();
+    for part in parts {
+        hasher.update(part).unwrap();
+    }
+    let digest = hasher.finalize().unwrap();
+    let mut result = [0u8; 32];
+    result.copy_from_slice(digest.as_ref());
+    result
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum VerifyError {
+    MissingValue,
+    InvalidNode,
+    /// The block does not hash to a node we already trust
+    HashMismatch,
+    /// The proof does not contain all the roots of the tree
+    MissingNodes,
+    MissingSignature,
+    InvalidSignature,
+}
+
+impl Display for VerifyError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        let message = match self {
+            VerifyError::MissingValue => "Remote sent data without a value",
+            VerifyError::InvalidNode => "Remote sent an invalid tree node",
+            VerifyError::HashMismatch => "Remote sent data that does not match the tree",
+            VerifyError::MissingNodes => "Remote sent an incomplete proof",
+            VerifyError::MissingSignature => "Remote sent an unsigned proof",
+            VerifyError::InvalidSignature => "Remote sent a proof with an invalid signature",
+        };
+        write!(f, "{}", message)
+    }
+}
+
+/// Checks incoming `Data` against the tree of a feed.
+///
+/// Starts out trusting only the roots it is given, and then every node of the proofs it
+/// verified.
+#[derive(Clone, Debug)]
+pub struct Verifier {
+    key_pair: KeyPair,
+    nodes: HashMap<u64, Node>,
+}
+
+impl Verifier {
+    pub fn new(key: Key) -> Self {
+        Verifier {
+            key_pair: KeyPair::public(key),
+            nodes: HashMap::new(),
+        }
+    }
+
+    /// Trusts `roots` without checking their signature, e.g. the roots of the local tree.
+    pub fn add_roots(&mut self, roots: Vec<Node>) {
+        for root in roots {
+            self.nodes.insert(root.index, root);
+        }
+    }
+
+    pub fn verify(&mut self, data: &schema::Data) -> Result<(), VerifyError> {
+        if !data.has_value() {
+            return Err(VerifyError::MissingValue);
+        }
+        // Keeps the flat-tree math below from overflowing
+        if data.get_index() >= MAX_BLOCKS {
+            return Err(VerifyError::InvalidNode);
+        }
+        let mut proof = HashMap::new();
+        for node in data.get_nodes() {
+            let node = Node::try_from(node).map_err(|_| VerifyError::InvalidNode)?;
+            if node.index >= 2 * MAX_BLOCKS {
+                return Err(VerifyError::InvalidNode);
+            }
+            proof.insert(node.index, node);
+        }
+
+        let mut verified = Vec::new();
+        let mut node = Node::leaf(data.get_index(), data.get_value());
+        loop {
+            if let Some(trusted) = self.nodes.get(&node.index) {
+                if trusted.hash != node.hash {
+                    return Err(VerifyError::HashMismatch);
+                }
+                break;
+            }
+
+            let sibling_index = sibling(node.index);
+            let sibling = proof
+                .remove(&sibling_index)
+                .or_else(|| self.nodes.get(&sibling_index).cloned());
+            match sibling {
+                Some(sibling) => {
+                    let parent = Node::parent(&node, &sibling);
+                    verified.push(node);
+                    verified.push(sibling);
+                    node = parent;
+                }
+                None => {
+                    // `node` is a root, the rest of the proof should be the other roots
+                    proof.insert(node.index, node);
+                    let roots = self.roots(proof)?;
+                    self.verify_signature(&roots, data)?;
+                    verified.extend(roots);
+                    break;
+                }
+            }
+        }
+
+        for node in verified {
+            self.nodes.insert(node.index, node);
+        }
+        Ok(())
+    }
+
+    fn roots(&self, mut candidates: HashMap<u64, Node>) -> Result<Vec<Node>, VerifyError> {
+        let last = *candidates.keys().max().unwrap();
+        let length = right_span(last) / 2 + 1;
+        full_roots(2 * length)
+            .into_iter()
+            .map(|index| {
+                candidates
+                    .remove(&index)
+                    .or_else(|| self.nodes.get(&index).cloned())
+                    .ok_or(VerifyError::MissingNodes)
+            })
+            .collect()
+    }
+
+    fn verify_signature(&self, roots: &[Node], data: &schema::Data) -> Result<(), VerifyError> {
+        if !data.has_signature() {
+            return Err(VerifyError::MissingSignature);
+        }
+        if self.key_pair.verify_roots(roots, data.get_signature()) {
+            Ok(())
+        } else {
+            Err(VerifyError::InvalidSignature)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use sodiumoxide::crypto::sign;
+
+    use super::*;
+
+    // A tree of 3 blocks: leaves 0, 2 and 4, and their roots 1 and 4
+    fn tree() -> (Vec<Node>, sign::PublicKey, sign::SecretKey) {
+        sodiumoxide::init().unwrap();
+        let (public_key, secret_key) = sign::gen_keypair();
+        let leaves = vec![
+            Node::leaf(0, b"foo"),
+            Node::leaf(1, b"bar"),
+            Node::leaf(2, b"baz"),
+        ];
+        (leaves, public_key, secret_key)
+    }
+
+    fn data(index: u64, value: &[u8], nodes: &[&Node]) -> schema::Data {
+        let mut data = schema::Data::new();
+        data.set_index(index);
+        data.set_value(value.to_vec());
+        for node in nodes {
+            let mut proof_node = schema::Data_Node::new();
+            proof_node.set_index(node.index);
+            proof_node.set_hash(node.hash.to_vec());
+            proof_node.set_size(node.size);
+            data.mut_nodes().push(proof_node);
+        }
+        data
+    }
+
+    #[test]
+    fn test_verify() {
+        let (leaves, public_key, secret_key) = tree();
+        let root = Node::parent(&leaves[0], &leaves[1]);
+        let roots = vec![root, leaves[2].clone()];
+        let signature = sign::sign_detached(&tree_hash(&roots), &secret_key);
+
+        let mut verifier = Verifier::new(Key(public_key.0));
+        let mut first = data(0, b"foo", &[&leaves[1], &leaves[2]]);
+        assert_eq!(verifier.verify(&first), Err(VerifyError::MissingSignature));
+        first.set_signature(signature.as_ref().to_vec());
+        assert_eq!(verifier.verify(&first), Ok(()));
+
+        // The leaf of block 1 is trusted now
+        assert_eq!(verifier.verify(&data(1, b"bar", &[])), Ok(()));
+        assert_eq!(
+            verifier.verify(&data(1, b"baz", &[])),
+            Err(VerifyError::HashMismatch)
+        );
+    }
+
+    #[test]
+    fn test_verify_rejects_invalid_proofs() {
+        let (leaves, public_key, secret_key) = tree();
+        let root = Node::parent(&leaves[0], &leaves[1]);
+        let signature = sign::sign_detached(&tree_hash(&[root, leaves[2].clone()]), &secret_key);
+
+        let mut verifier = Verifier::new(Key(public_key.0));
+        let mut incomplete = data(0, b"foo", &[&leaves[2]]);
+        incomplete.set_signature(signature.as_ref().to_vec());
+        assert_eq!(verifier.verify(&incomplete), Err(VerifyError::MissingNodes));
+
+        let mut tampered = data(0, b"fou", &[&leaves[1], &leaves[2]]);
+        tampered.set_signature(signature.as_ref().to_vec());
+        assert_eq!(
+            verifier.verify(&tampered),
+            Err(VerifyError::InvalidSignature)
+        );
+    }
+
+    #[test]
+    fn test_verify_with_trusted_roots() {
+        let (leaves, public_key, _) = tree();
+        let root = Node::parent(&leaves[0], &leaves[1]);
+
+        let mut verifier = Verifier::new(Key(public_key.0));
+        verifier.add_roots(vec![root, leaves[2].clone()]);
+        assert_eq!(verifier.verify(&data(0, b"foo", &[&leaves[1]])), Ok(()));
+        assert_eq!(verifier.verify(&data(2, b"baz", &[])), Ok(()));
+        assert_eq!(
+            verifier.verify(&data(2, b"bar", &[])),
+            Err(VerifyError::HashMismatch)
+        );
+    }
+}
@@ -2,16 +2,30 @@ use std::borrow::Cow;
 use std::cell::{Cell, RefCell};
 use std::collections::HashMap;
 use std::convert::{TryFrom, TryInto};
-use std::rc::Rc;
-
+use std::fmt::{self, Debug, Display, Formatter};
+use std::mem;
+use std::ops::Range;
+use std::rc::{Rc, Weak};
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+use data_encoding::{Encoding, Specification, HEXLOWER, HEXLOWER_PERMISSIVE};
 use integer_encoding::VarInt;
 use protobuf::parse_from_bytes;
-use slog::{o, trace, Drain, Logger};
+use slog::Logger;
 use sodiumoxide::crypto::generichash;
 
+use crate::bitfield::Bitfield;
 use crate::crypto_stream::{crypto_stream_xor_instance, Xor};
-use crate::feed::{Feed, FeedEvent, FeedEventEmitter, FeedStream};
+use crate::extension::{ConnectionExtension, Entry, Extension, Extensions};
+use crate::feed::{Feed, FeedEvent, FeedEventEmitter, FeedStream, Info};
+use crate::logging::{global_trace, trace, Bytes, Log, Payload};
+use crate::merkle::Verifier;
+use crate::remote_info::RemoteInfo;
+use crate::requests::{RequestError, Requests, DEFAULT_MAX_REQUESTS, DEFAULT_REQUEST_TIMEOUT};
 use crate::schema;
+use crate::stats::{Counters, FeedStats, Stats};
+use crate::storage::{FeedStorage, StorageError};
 use crate::wire_format;
 
 #[derive(Copy, Clone, Debug, Eq, PartialEq)]
@@ -57,6 +71,26 @@ pub enum Message {
     Extension(Vec<u8>),
 }
 
+impl MessageType {
+    /// Labels the metrics.
+    #[cfg(feature = "metrics")]
+    pub(crate) fn name(self) -> &'static str {
+        match self {
+            MessageType::Feed => "feed",
+            MessageType::Handshake => "handshake",
+            MessageType::Info => "info",
+            MessageType::Have => "have",
+            MessageType::Unhave => "unhave",
+            MessageType::Want => "want",
+            MessageType::Unwant => "unwant",
+            MessageType::Request => "request",
+            MessageType::Cancel => "cancel",
+            MessageType::Data => "data",
+            MessageType::Extension => "extension",
+        }
+    }
+}
+
 impl Message {
     pub(crate) fn r#type(&self) -> MessageType {
         match self {
@@ -75,12 +109,26 @@ impl Message {
     }
 }
 
-#[derive(Clone, Eq, PartialEq, Hash, Debug)]
+#[derive(Clone, Eq, PartialEq, Hash)]
 pub struct Key(pub [u8; 32]);
 
+impl Key {
+    /// The hash of the key, used to find peers and to refer to the feed on the wire without
+    /// revealing the key.
+    pub fn discovery_key(&self) -> DiscoveryKey {
+        discovery_key(&self.0)
+    }
+}
+
 #[derive(Clone, Eq, PartialEq, Hash, Debug)]
 pub struct DiscoveryKey([u8; 32]);
 
+impl DiscoveryKey {
+    pub fn as_bytes(&self) -> &[u8; 32] {
+        &self.0
+    }
+}
+
 impl TryFrom<&[u8]> for DiscoveryKey {
     type Error = ();
 
@@ -89,19 +137,25 @@ impl TryFrom<&[u8]> for DiscoveryKey {
         if value.len() != bytes.len() {
             Err(())
         } else {
-            bytes.copy_from_slice(&value);
+            bytes.copy_from_slice(value);
             Ok(DiscoveryKey(bytes))
         }
     }
 }
 
-#[derive(Clone, Eq, PartialEq, Hash, Debug)]
+#[derive(Clone, Eq, PartialEq, Hash)]
 struct Nonce([u8; 24]);
 
+impl Debug for Nonce {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "Nonce(..)")
+    }
+}
+
 impl Nonce {
-    fn new() -> Nonce {
+    fn new(rng: &Option<Rng>) -> Nonce {
         let mut bytes = [0; 24];
-        random_bytes_into(&mut bytes);
+        fill_random(rng, &mut bytes);
         Nonce(bytes)
     }
 }
@@ -114,13 +168,13 @@ impl TryFrom<&[u8]> for Nonce {
         if value.len() != bytes.len() {
             Err(())
         } else {
-            bytes.copy_from_slice(&value);
+            bytes.copy_from_slice(value);
             Ok(Nonce(bytes))
         }
     }
 }
 
-#[derive(Clone, Eq, PartialEq, Hash, Debug)]
+#[derive(Clone, Eq, PartialEq, Hash)]
 pub struct Id(pub(crate) [u8; 32]);
 
 impl TryFrom<&[u8]> for Id {
@@ -131,47 +185,138 @@ impl TryFrom<&[u8]> for Id {
         if value.len() != bytes.len() {
             Err(())
         } else {
-            bytes.copy_from_slice(&value);
+            bytes.copy_from_slice(value);
             Ok(Id(bytes))
         }
     }
 }
 
+/// Returned when parsing a `Key`, `DiscoveryKey` or `Id` fails.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct InvalidKey;
+
+impl Display for InvalidKey {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "Expected 32 bytes in hex or z-base32")
+    }
+}
+
+// z-base32, as printed by the nodejs tools
+fn zbase32() -> Encoding {
+    let mut spec = Specification::new();
+    spec.symbols.push_str("ybndrfg8ejkmcpqxot1uwisza345h769");
+    spec.encoding().unwrap()
+}
+
+/// Displayed in hex, or in z-base32 with `{:#}`. Parsed from either.
+macro_rules! key_format {
+    ($key:ident) => {
+        impl Display for $key {
+            fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+                if f.alternate() {
+                    write!(f, "{}", zbase32().encode(&self.0))
+                } else {
+                    write!(f, "{}", HEXLOWER.encode(&self.0))
+                }
+            }
+        }
+
+        impl FromStr for $key {
+            type Err = InvalidKey;
+
+            fn from_str(s: &str) -> Result<Self, Self::Err> {
+                let bytes = if s.len() == 64 {
+                    HEXLOWER_PERMISSIVE.decode(s.as_bytes())
+                } else {
+                    zbase32().decode(s.as_bytes())
+                };
+                let bytes = bytes.map_err(|_| InvalidKey)?;
+                let mut key = [0u8; 32];
+                if bytes.len() != key.len() {
+                    return Err(InvalidKey);
+                }
+                key.copy_from_slice(&bytes);
+                Ok($key(key))
+            }
+        }
+    };
+}
+
+key_format!(Key);
+key_format!(DiscoveryKey);
+key_format!(Id);
+
+// A feed key is a capability: whoever knows it can read the feed. `Debug` (and so every log)
+// only shows the start of its discovery key, `Display` reveals it.
+impl Debug for Key {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "Key({}..)", fingerprint(&self.discovery_key().0))
+    }
+}
+
+// Only a fingerprint of the hash, `Display` reveals it.
+impl Debug for Id {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        let hash = generichash::hash(&self.0, Some(32), None).unwrap();
+        write!(f, "Id({}..)", fingerprint(hash.as_ref()))
+    }
+}
+
+fn fingerprint(hash: &[u8]) -> String {
+    HEXLOWER.encode(&hash[..4])
+}
+
+/// What a [`Stream`] did with the bytes handed to it.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Push {
+    /// The bytes were written through, nothing of them is left queued.
+    Accepted,
+    /// The bytes were queued by the stream. They count towards the protocol's high-water mark
+    /// until they are reported as flushed with [`Protocol::drain`].
+    WouldBlock,
+}
+
 pub trait Stream {
-    fn _push(&mut self, bytes: &mut [u8]);
+    fn _push(&mut self, bytes: &mut [u8]) -> Push;
+
+    /// Nothing will be pushed anymore, the protocol finalized.
+    fn _end(&mut self) {}
 }
 
+// Same as the default `highWaterMark` of nodejs streams
+const DEFAULT_HIGH_WATER_MARK: usize = 16 * 1024;
+
 // encoding length of 8*1024*1024
 const VARINT_8M_ENCODING_LENGTH: usize = 4;
 
 pub struct Protocol<E: FeedEventEmitter, S: Stream> {
-    log: Logger,
+    log: Log,
 
     stream: Rc<RefCell<S>>,
     emitter: Rc<RefCell<E>>,
 
-    pub(crate) id: Id,
-    pub(crate) live: bool,
-    pub(crate) ack: bool,
-    pub(crate) user_data: Option<Vec<u8>>,
-    pub(crate) remote_id: Rc<RefCell<Option<Id>>>,
-    pub(crate) remote_live: Rc<Cell<Option<bool>>>,
-    pub(crate) remote_ack: Rc<Cell<Option<bool>>>,
-    pub(crate) remote_user_data: Rc<RefCell<Option<Vec<u8>>>>,
+    id: Id,
+    live: bool,
+    ack: bool,
+    user_data: Option<Vec<u8>>,
+    remote: Rc<RefCell<Option<RemoteInfo>>>,
 
     destroyed: Rc<Cell<bool>>,
     encrypted: bool,
+    connection_key: ConnectionKey,
     key: Option<Key>,
     discovery_key: Option<DiscoveryKey>,
     remote_discovery_key: Option<DiscoveryKey>,
-    feeds: Vec<Rc<RefCell<Feed<FeedStreamHack<E, S>, FeedEventEmitterImpl>>>>,
-    extensions: Rc<RefCell<Vec<String>>>,
+    feeds: Vec<FeedRc<E, S>>,
+    extensions: Rc<Extensions>,
     remote_extensions: Rc<RefCell<Vec<Option<usize>>>>,
+    handshake_channel: Rc<Cell<Option<Channel>>>,
+    counters: Rc<RefCell<Counters>>,
     max_feeds: usize,
 
-    _local_feeds: Vec<Rc<RefCell<Feed<FeedStreamHack<E, S>, FeedEventEmitterImpl>>>>,
-    _remote_feeds: Vec<Option<Rc<RefCell<Feed<FeedStreamHack<E, S>, FeedEventEmitterImpl>>>>>,
-    _feeds: HashMap<DiscoveryKey, Rc<RefCell<Feed<FeedStreamHack<E, S>, FeedEventEmitterImpl>>>>,
+    _local_feeds: Vec<FeedRc<E, S>>,
+    _remote_feeds: Vec<Option<FeedRc<E, S>>>,
+    _feeds: Rc<RefCell<FeedMap<E, S>>>,
 
     _nonce: Option<Nonce>,
     _remote_nonce: Option<Nonce>,
@@ -186,94 +331,542 @@ pub struct Protocol<E: FeedEventEmitter, S: Stream> {
     _start: usize,
     _keep_alive: Rc<Cell<u8>>,
     _remote_keep_alive: u8,
+    high_water_mark: usize,
+    _queued: Rc<Cell<usize>>,
+    _needs_drain: Rc<Cell<bool>>,
+    _paused: bool,
+    max_requests: usize,
+    request_timeout: Duration,
+    rng: Option<Rng>,
+    key_lookup: Option<KeyLookup>,
 }
 
-#[derive(Clone, Debug)]
-pub struct ProtocolOpts {
-    pub id: Option<Id>,
-    pub live: Option<bool>,
-    pub user_data: Option<Vec<u8>>,
-    pub ack: Option<bool>,
-    pub encrypted: Option<bool>,
-    pub extensions: Option<Vec<String>>,
+/// Which key encrypts the connection.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub enum ConnectionKey {
+    /// The key of the first feed, both sides have to open the same feed first.
+    #[default]
+    FirstFeed,
+    /// A key known to both sides in advance, the first feeds can differ.
+    Fixed(Key),
 }
 
-impl ProtocolOpts {
-    fn new() -> Self {
-        ProtocolOpts::default()
+/// Returns the key of the feed with the given discovery key, if it should be opened.
+pub type KeyLookup = Rc<dyn Fn(&DiscoveryKey) -> Option<Key>>;
+
+/// Fills `buf` with random bytes.
+pub type Rng = Rc<dyn Fn(&mut [u8])>;
+
+/// Returned by [`ProtocolBuilder::build`] for invalid settings.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum BuildError {
+    EmptyExtension,
+    /// Two registered extensions have this name.
+    DuplicateExtension(String),
+    /// Channels are numbered with a byte, at most 256 feeds fit.
+    InvalidMaxFeeds(usize),
+    InvalidHighWaterMark,
+    InvalidMaxRequests,
+    InvalidRequestTimeout,
+}
+
+impl Display for BuildError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            BuildError::EmptyExtension => write!(f, "Extension names must not be empty"),
+            BuildError::DuplicateExtension(name) => {
+                write!(f, "Extension {:?} is registered twice", name)
+            }
+            BuildError::InvalidMaxFeeds(max_feeds) => {
+                write!(f, "Max feeds must be between 1 and 256, got {}", max_feeds)
+            }
+            BuildError::InvalidHighWaterMark => write!(f, "High-water mark must not be 0"),
+            BuildError::InvalidMaxRequests => write!(f, "Max requests must not be 0"),
+            BuildError::InvalidRequestTimeout => write!(f, "Request timeout must not be 0"),
+        }
     }
 }
 
-impl Default for ProtocolOpts {
+#[derive(Clone)]
+pub struct ProtocolBuilder {
+    log: Option<Logger>,
+    id: Option<Id>,
+    live: bool,
+    user_data: Option<Vec<u8>>,
+    ack: bool,
+    encrypted: bool,
+    connection_key: ConnectionKey,
+    extensions: Vec<String>,
+    handlers: Vec<(String, Entry)>,
+    max_feeds: usize,
+    high_water_mark: usize,
+    max_requests: usize,
+    request_timeout: Duration,
+    rng: Option<Rng>,
+    key_lookup: Option<KeyLookup>,
+}
+
+impl Debug for ProtocolBuilder {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        f.debug_struct("ProtocolBuilder")
+            .field("id", &self.id)
+            .field("live", &self.live)
+            .field(
+                "user_data",
+                &self.user_data.as_ref().map(|data| Bytes(data)),
+            )
+            .field("ack", &self.ack)
+            .field("encrypted", &self.encrypted)
+            .field("connection_key", &self.connection_key)
+            .field("extensions", &self.extensions)
+            .field(
+                "handlers",
+                &self
+                    .handlers
+                    .iter()
+                    .map(|(name, _)| name)
+                    .collect::<Vec<_>>(),
+            )
+            .field("max_feeds", &self.max_feeds)
+            .field("high_water_mark", &self.high_water_mark)
+            .field("max_requests", &self.max_requests)
+            .field("request_timeout", &self.request_timeout)
+            .field("rng", &self.rng.is_some())
+            .field("key_lookup", &self.key_lookup.is_some())
+            .finish()
+    }
+}
+
+impl Default for ProtocolBuilder {
     fn default() -> Self {
-        ProtocolOpts {
+        ProtocolBuilder {
+            log: None,
             id: None,
-            live: None,
+            live: false,
             user_data: None,
-            ack: None,
-            encrypted: None,
-            extensions: None,
+            ack: false,
+            encrypted: true,
+            connection_key: ConnectionKey::FirstFeed,
+            extensions: Vec::new(),
+            handlers: Vec::new(),
+            max_feeds: 256,
+            high_water_mark: DEFAULT_HIGH_WATER_MARK,
+            max_requests: DEFAULT_MAX_REQUESTS,
+            request_timeout: DEFAULT_REQUEST_TIMEOUT,
+            rng: None,
+            key_lookup: None,
         }
     }
 }
 
+impl ProtocolBuilder {
+    pub fn new() -> Self {
+        ProtocolBuilder::default()
+    }
+
+    pub fn logger(mut self, log: Logger) -> Self {
+        self.log = Some(log);
+        self
+    }
+
+    /// Random by default.
+    pub fn id(mut self, id: Id) -> Self {
+        self.id = Some(id);
+        self
+    }
+
+    pub fn live(mut self, live: bool) -> Self {
+        self.live = live;
+        self
+    }
+
+    pub fn user_data(mut self, user_data: Vec<u8>) -> Self {
+        self.user_data = Some(user_data);
+        self
+    }
+
+    /// Sends `user_data` encoded as JSON, see `RemoteInfo::user_data_json`.
+    #[cfg(feature = "json-user-data")]
+    pub fn user_data_json<T: serde::Serialize>(self, user_data: &T) -> serde_json::Result<Self> {
+        Ok(self.user_data(serde_json::to_vec(user_data)?))
+    }
+
+    /// Sends `user_data` encoded as protobuf, see `RemoteInfo::user_data_protobuf`.
+    #[cfg(feature = "protobuf-user-data")]
+    pub fn user_data_protobuf<M: protobuf::Message>(
+        self,
+        user_data: &M,
+    ) -> protobuf::ProtobufResult<Self> {
+        Ok(self.user_data(user_data.write_to_bytes()?))
+    }
+
+    pub fn ack(mut self, ack: bool) -> Self {
+        self.ack = ack;
+        self
+    }
+
+    pub fn encrypted(mut self, encrypted: bool) -> Self {
+        self.encrypted = encrypted;
+        self
+    }
+
+    /// Ignored if not encrypted, then the first feeds can always differ.
+    pub fn connection_key(mut self, connection_key: ConnectionKey) -> Self {
+        self.connection_key = connection_key;
+        self
+    }
+
+    /// In any order, duplicates are dropped.
+    pub fn extensions<I: IntoIterator<Item = String>>(mut self, extensions: I) -> Self {
+        self.extensions.extend(extensions);
+        self
+    }
+
+    pub fn extension<N: Into<String>>(mut self, name: N) -> Self {
+        self.extensions.push(name.into());
+        self
+    }
+
+    /// Handles the messages of `extension` on every feed, its name is added to the
+    /// extensions. Clones of the builder share the handler.
+    pub fn register<X: Extension>(mut self, extension: X) -> Self {
+        self.handlers.push(Entry::feed(extension));
+        self
+    }
+
+    /// Handles the messages of the connection level `extension`, its name is added to the
+    /// extensions. Clones of the builder share the handler.
+    pub fn register_connection<X: ConnectionExtension>(mut self, extension: X) -> Self {
+        self.handlers.push(Entry::connection(extension));
+        self
+    }
+
+    pub fn max_feeds(mut self, max_feeds: usize) -> Self {
+        self.max_feeds = max_feeds;
+        self
+    }
+
+    pub fn high_water_mark(mut self, high_water_mark: usize) -> Self {
+        self.high_water_mark = high_water_mark;
+        self
+    }
+
+    /// The default of feeds not setting `FeedOptions::max_requests`.
+    pub fn max_requests(mut self, max_requests: usize) -> Self {
+        self.max_requests = max_requests;
+        self
+    }
+
+    /// The default of feeds not setting `FeedOptions::request_timeout`.
+    pub fn request_timeout(mut self, request_timeout: Duration) -> Self {
+        self.request_timeout = request_timeout;
+        self
+    }
+
+    /// Generates the id and the nonce, sodiumoxide is used by default.
+    pub fn rng<F: Fn(&mut [u8]) + 'static>(mut self, rng: F) -> Self {
+        self.rng = Some(Rc::new(rng));
+        self
+    }
+
+    /// Consulted when the remote opens a feed that was not opened locally yet. The feed is
+    /// opened right away if it returns a key, parsing does not wait for `Protocol::feed`. A key
+    /// that does not match the discovery key is ignored, as if `None` was returned.
+    pub fn key_lookup<F: Fn(&DiscoveryKey) -> Option<Key> + 'static>(
+        mut self,
+        key_lookup: F,
+    ) -> Self {
+        self.key_lookup = Some(Rc::new(key_lookup));
+        self
+    }
+
+    pub fn build<E: FeedEventEmitter, S: Stream>(
+        mut self,
+        emitter: E,
+        stream: S,
+    ) -> Result<Protocol<E, S>, BuildError> {
+        let extensions = Extensions::new(
+            mem::take(&mut self.extensions),
+            mem::take(&mut self.handlers),
+        )
+        .map_err(BuildError::DuplicateExtension)?;
+        if extensions.names().iter().any(String::is_empty) {
+            return Err(BuildError::EmptyExtension);
+        }
+        if self.max_feeds == 0 || self.max_feeds > 256 {
+            return Err(BuildError::InvalidMaxFeeds(self.max_feeds));
+        }
+        if self.high_water_mark == 0 {
+            return Err(BuildError::InvalidHighWaterMark);
+        }
+        if self.max_requests == 0 {
+            return Err(BuildError::InvalidMaxRequests);
+        }
+        if self.request_timeout == Duration::from_secs(0) {
+            return Err(BuildError::InvalidRequestTimeout);
+        }
+        Ok(Protocol::new(self, extensions, emitter, stream))
+    }
+}
+
 #[derive(Clone, Debug)]
 pub struct FeedOptions {
     pub discovery_key: Option<DiscoveryKey>,
+    /// Maximum number of requests in flight on this feed
+    pub max_requests: Option<usize>,
+    pub request_timeout: Option<Duration>,
 }
 
 impl Default for FeedOptions {
     fn default() -> Self {
         FeedOptions {
             discovery_key: None,
+            max_requests: None,
+            request_timeout: None,
+        }
+    }
+}
+
+/// Returned by [`Protocol::feed`] if the feed could not be opened.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum FeedError {
+    Destroyed,
+    /// `ProtocolBuilder::max_feeds` feeds are open already.
+    TooManyFeeds,
+    /// The first feed does not match the first feed of the remote, the connection can not be
+    /// decrypted. See `ConnectionKey`.
+    KeyMismatch,
+}
+
+impl Display for FeedError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            FeedError::Destroyed => write!(f, "Protocol is destroyed"),
+            FeedError::TooManyFeeds => write!(f, "Too many feeds are open"),
+            FeedError::KeyMismatch => write!(f, "First feed does not match the remote one"),
         }
     }
 }
 
+/// A feed opened with [`Protocol::feed`]. Clones refer to the same feed.
+pub struct FeedHandle<E: FeedEventEmitter, S: Stream>(FeedRc<E, S>);
+
+impl<E: FeedEventEmitter, S: Stream> Clone for FeedHandle<E, S> {
+    fn clone(&self) -> Self {
+        FeedHandle(self.0.clone())
+    }
+}
+
+impl<E: FeedEventEmitter, S: Stream> Debug for FeedHandle<E, S> {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        self.0.borrow().fmt(f)
+    }
+}
+
+impl<E: FeedEventEmitter, S: Stream> FeedHandle<E, S> {
+    pub fn key(&self) -> Option<Key> {
+        self.0.borrow().key.clone()
+    }
+
+    pub fn discovery_key(&self) -> Option<DiscoveryKey> {
+        self.0.borrow().discovery_key.clone()
+    }
+
+    /// Stops sending and handling messages on this feed.
+    pub fn close(&self) {
+        self.0.borrow_mut()._onclose();
+        self.update_end();
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.0.borrow().is_closed()
+    }
+
+    /// See `Feed::info`, all the sending methods return `false` if the caller should wait for
+    /// a `FeedEvent::Drain` or the feed is closed.
+    pub fn info(&self, info: Info) -> bool {
+        let sent = self.0.borrow_mut().info(info);
+        self.update_end();
+        sent
+    }
+
+    pub fn set_uploading(&self, uploading: bool) -> bool {
+        self.0.borrow_mut().set_uploading(uploading)
+    }
+
+    pub fn set_downloading(&self, downloading: bool) -> bool {
+        let sent = self.0.borrow_mut().set_downloading(downloading);
+        self.update_end();
+        sent
+    }
+
+    pub fn local_info(&self) -> Info {
+        self.0.borrow().local_info()
+    }
+
+    pub fn remote_info(&self) -> Info {
+        self.0.borrow().remote_info()
+    }
+
+    pub fn remote_uploading(&self) -> bool {
+        self.0.borrow().remote_uploading()
+    }
+
+    pub fn remote_downloading(&self) -> bool {
+        self.0.borrow().remote_downloading()
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.0.borrow().is_finished()
+    }
+
+    /// A local change can be the last one the protocol waits for to finalize.
+    fn update_end(&self) {
+        let stream = self.0.borrow().stream().clone();
+        stream._update_end();
+    }
+
+    pub fn have(&self, have: schema::Have) -> bool {
+        self.0.borrow_mut().have(have)
+    }
+
+    pub fn unhave(&self, unhave: schema::Unhave) -> bool {
+        self.0.borrow_mut().unhave(unhave)
+    }
+
+    pub fn want(&self, want: schema::Want) -> bool {
+        self.0.borrow_mut().want(want)
+    }
+
+    pub fn unwant(&self, unwant: schema::Unwant) -> bool {
+        self.0.borrow_mut().unwant(unwant)
+    }
+
+    pub fn request(&self, request: schema::Request, now: Instant) -> Result<bool, RequestError> {
+        self.0.borrow_mut().request(request, now)
+    }
+
+    pub fn cancel(&self, index: u64) -> bool {
+        self.0.borrow_mut().cancel(index)
+    }
+
+    pub fn tick(&self, now: Instant) {
+        self.0.borrow_mut().tick(now)
+    }
+
+    /// The number of requests waiting for their `Data`.
+    pub fn pending_requests(&self) -> usize {
+        self.0.borrow().requests().len()
+    }
+
+    pub fn data(&self, data: schema::Data) -> bool {
+        self.0.borrow_mut().data(data)
+    }
+
+    pub fn pending_acks(&self) -> usize {
+        self.0.borrow().pending_acks()
+    }
+
+    pub fn stats(&self) -> FeedStats {
+        self.0.borrow().stats()
+    }
+
+    pub fn remote_has(&self, index: u64) -> bool {
+        self.0.borrow().remote_has(index)
+    }
+
+    pub fn remote_wants(&self, index: u64) -> bool {
+        self.0.borrow().remote().remote_wants(index)
+    }
+
+    /// Ranges of blocks the remote has, but are not in the storage.
+    pub fn missing(&self) -> Vec<Range<u64>> {
+        let feed = self.0.borrow();
+        let empty = Bitfield::default();
+        let local = feed.storage().map_or(&empty, |storage| storage.bitfield());
+        feed.remote().missing(local).collect()
+    }
+
+    pub fn set_verifier(&self, verifier: Verifier) {
+        self.0.borrow_mut().set_verifier(verifier)
+    }
+
+    pub fn set_storage<T: FeedStorage + 'static>(&self, storage: T) -> Result<(), StorageError> {
+        self.0.borrow_mut().set_storage(storage)
+    }
+
+    /// Calls `f` with the storage, if there is one.
+    pub fn with_storage<R, F: FnOnce(&dyn FeedStorage) -> R>(&self, f: F) -> Option<R> {
+        self.0.borrow().storage().map(f)
+    }
+
+    pub fn is_writable(&self) -> bool {
+        self.0.borrow().is_writable()
+    }
+
+    pub fn append(&self, value: &[u8]) -> Result<bool, StorageError> {
+        self.0.borrow_mut().append(value)
+    }
+
+    pub fn extension(&self, name: &str, payload: &[u8]) -> bool {
+        self.0.borrow_mut().extension(name, payload)
+    }
+
+    pub fn send_extension<X: Extension>(&self, message: &X::Message) -> bool {
+        self.0.borrow_mut().send_extension::<X>(message)
+    }
+}
+
 impl<E: FeedEventEmitter, S: Stream> Protocol<E, S> {
-    pub fn new<L: Into<Option<Logger>>>(
-        logger: L,
+    fn new(
+        builder: ProtocolBuilder,
+        extensions: Extensions,
         emitter: E,
         stream: S,
-        opts: &ProtocolOpts,
     ) -> Protocol<E, S> {
-        let log = logger
-            .into()
-            .unwrap_or_else(|| Logger::root(slog_stdlog::StdLog.fuse(), o!()));
-        trace!(log, "Protocol::new({:?})", opts);
+        let log = Log::new(builder.log.clone());
+        trace!(log, "Protocol::new({:?})", builder);
         debug_assert_eq!(
             VARINT_8M_ENCODING_LENGTH,
             VarInt::required_space(8 * 1024 * 1024)
         );
 
+        let id = match builder.id {
+            Some(id) => id,
+            None => random_id(&builder.rng),
+        };
+
         Protocol {
             log,
 
             stream: Rc::new(RefCell::new(stream)),
             emitter: Rc::new(RefCell::new(emitter)),
 
-            id: opts.id.clone().unwrap_or_else(random_id),
-            live: opts.live.unwrap_or(false),
-            ack: opts.ack.unwrap_or(false),
-            user_data: opts.user_data.clone(),
-            remote_id: Rc::new(RefCell::new(None)),
-            remote_live: Rc::new(Cell::new(None)),
-            remote_ack: Rc::new(Cell::new(None)),
-            remote_user_data: Rc::new(RefCell::new(None)),
+            id,
+            live: builder.live,
+            ack: builder.ack,
+            user_data: builder.user_data,
+            remote: Rc::new(RefCell::new(None)),
 
             destroyed: Rc::new(Cell::new(false)),
-            encrypted: opts.encrypted.unwrap_or(true),
-            key: None,
+            encrypted: builder.encrypted,
+            key: match builder.connection_key {
+                ConnectionKey::Fixed(ref key) => Some(key.clone()),
+                ConnectionKey::FirstFeed => None,
+            },
+            connection_key: builder.connection_key,
             discovery_key: None,
             remote_discovery_key: None,
             feeds: Vec::new(),
-            extensions: Rc::new(RefCell::new(opts.extensions.clone().unwrap_or_default())),
+            extensions: Rc::new(extensions),
             remote_extensions: Rc::new(RefCell::new(vec![])),
-            max_feeds: 256,
+            handshake_channel: Rc::new(Cell::new(None)),
+            counters: Rc::new(RefCell::new(Counters::default())),
+            max_feeds: builder.max_feeds,
 
             _local_feeds: Vec::new(),
             _remote_feeds: Vec::new(),
-            _feeds: HashMap::new(),
+            _feeds: Rc::new(RefCell::new(HashMap::new())),
 
             _nonce: None,
             _remote_nonce: None,
@@ -288,21 +881,25 @@ impl<E: FeedEventEmitter, S: Stream> Protocol<E, S> {
             _start: 0,
             _keep_alive: Rc::new(Cell::new(0)),
             _remote_keep_alive: 0,
+            high_water_mark: builder.high_water_mark,
+            _queued: Rc::new(Cell::new(0)),
+            _needs_drain: Rc::new(Cell::new(false)),
+            _paused: false,
+            max_requests: builder.max_requests,
+            request_timeout: builder.request_timeout,
+            rng: builder.rng,
+            key_lookup: builder.key_lookup,
         }
     }
 
     pub(crate) fn has(&self, key: &Key) -> bool {
-        self._feeds.contains_key(&discovery_key(&key.0))
+        self._feeds.borrow().contains_key(&discovery_key(&key.0))
     }
 
-    pub fn feed(
-        &mut self,
-        key: &Key,
-        opts: FeedOptions,
-    ) -> Option<Rc<RefCell<Feed<FeedStreamHack<E, S>, FeedEventEmitterImpl>>>> {
+    pub fn feed(&mut self, key: &Key, opts: FeedOptions) -> Result<FeedHandle<E, S>, FeedError> {
         trace!(self.log, "Protocol::feed({:?})", opts);
         if self.destroyed.get() {
-            return None;
+            return Err(FeedError::Destroyed);
         }
 
         let dk = opts.discovery_key.unwrap_or_else(|| discovery_key(&key.0));
@@ -310,52 +907,55 @@ impl<E: FeedEventEmitter, S: Stream> Protocol<E, S> {
         let ch = self._feed(&dk);
 
         if ch.borrow().id.is_some() {
-            return Some(ch.clone());
+            return Ok(FeedHandle(ch));
         }
 
         if self._local_feeds.len() >= self.max_feeds {
-            self._too_many_feeds();
-            return None;
+            return Err(FeedError::TooManyFeeds);
         }
 
         let id = self._local_feeds.len();
-        ch.borrow_mut().id = Some(Channel(id as u8));
+        ch.borrow_mut().set_id(Channel(id as u8));
         self._local_feeds.push(ch.clone());
         ch.borrow_mut().key = Some(key.clone());
         ch.borrow_mut().discovery_key = Some(dk.clone());
+        ch.borrow_mut().requests = Requests::new(
+            opts.max_requests.unwrap_or(self.max_requests),
+            opts.request_timeout.unwrap_or(self.request_timeout),
+        );
 
         self.feeds.push(ch.clone());
 
-        let first = self.key.is_none();
+        let first = self.discovery_key.is_none();
         let mut feed = schema::Feed::new();
         feed.set_discoveryKey(Vec::from(&dk.0[..]));
 
         trace!(self.log, "Protocol::feed: first: {}", first);
         if first {
-            self.key = Some(key.clone());
+            if self.key.is_none() {
+                self.key = Some(key.clone());
+            }
             self.discovery_key = Some(dk.clone());
 
             if !self._same_key() {
                 trace!(self.log, "Protocol::feed: not same key");
-                return None;
+                return Err(FeedError::KeyMismatch);
             }
 
             trace!(self.log, "Protocol::feed: encrypted: {}", self.encrypted);
             if self.encrypted {
-                let nonce = Nonce::new();
-                trace!(self.log, "Protocol::feed: nonce: {:?}", nonce);
+                let nonce = Nonce::new(&self.rng);
                 self._nonce = Some(nonce.clone());
                 feed.set_nonce(Vec::from(nonce.0.as_ref()));
 
-                trace!(self.log, "Protocol::feed: key: {:?}", self.key);
                 *self._xor.borrow_mut() = Some(crypto_stream_xor_instance(
                     &self._nonce.as_ref().unwrap().0,
                     &self.key.as_ref().unwrap().0,
                 ));
                 trace!(
                     self.log,
-                    "Protocol::feed: remote_nonce: {:?}",
-                    self._remote_nonce
+                    "Protocol::feed: remote_nonce: {}",
+                    self._remote_nonce.is_some()
                 );
                 if let Some(ref remote_nonce) = self._remote_nonce {
                     self._remote_xor = Some(crypto_stream_xor_instance(
@@ -381,10 +981,14 @@ impl<E: FeedEventEmitter, S: Stream> Protocol<E, S> {
                 .update(&r#box.clone(), &mut r#box);
         }
         self._keep_alive.set(0);
+        self.counters.borrow_mut().sent.add(MessageType::Feed);
+        #[cfg(feature = "metrics")]
+        metrics::counter!("hypercore_protocol_frames_sent", "type" => MessageType::Feed.name())
+            .increment(1);
         self.push(&mut r#box);
 
         if self.destroyed.get() {
-            return None;
+            return Err(FeedError::Destroyed);
         }
 
         if first {
@@ -394,9 +998,11 @@ impl<E: FeedEventEmitter, S: Stream> Protocol<E, S> {
             if let Some(ref user_data) = self.user_data {
                 handshake.set_userData(user_data.clone())
             }
-            handshake.set_extensions(self.extensions.borrow()[..].into());
+            handshake.set_extensions(self.extensions.names().into());
             handshake.set_ack(self.ack);
 
+            self.handshake_channel.set(ch.borrow().id);
+            self.counters.borrow_mut().handshake_sent = Some(Instant::now());
             ch.borrow_mut().handshake(handshake);
         }
 
@@ -406,11 +1012,184 @@ impl<E: FeedEventEmitter, S: Stream> Protocol<E, S> {
             ch.borrow_mut()._buffer = None
         }
 
-        Some(ch.clone())
+        Ok(FeedHandle(ch))
+    }
+
+    pub fn id(&self) -> &Id {
+        &self.id
+    }
+
+    pub fn live(&self) -> bool {
+        self.live
+    }
+
+    pub fn ack(&self) -> bool {
+        self.ack
+    }
+
+    pub fn user_data(&self) -> Option<&[u8]> {
+        self.user_data.as_deref()
+    }
+
+    pub fn encrypted(&self) -> bool {
+        self.encrypted
+    }
+
+    pub fn is_destroyed(&self) -> bool {
+        self.destroyed.get()
+    }
+
+    /// Whether the handshake of the remote arrived.
+    pub fn is_handshaken(&self) -> bool {
+        self.remote.borrow().is_some()
+    }
+
+    /// What the remote sent in its handshake, `None` before `FeedEvent::Handshake`.
+    pub fn remote(&self) -> Option<RemoteInfo> {
+        self.remote.borrow().clone()
+    }
+
+    pub fn remote_id(&self) -> Option<Id> {
+        self.remote
+            .borrow()
+            .as_ref()
+            .map(|remote| remote.id.clone())
+    }
+
+    pub fn remote_live(&self) -> Option<bool> {
+        self.remote.borrow().as_ref().map(|remote| remote.live)
+    }
+
+    /// The discovery keys of the feeds opened locally, in the order they were opened.
+    pub fn local_feeds(&self) -> Vec<DiscoveryKey> {
+        self._local_feeds
+            .iter()
+            .filter_map(|feed| feed.borrow().discovery_key.clone())
+            .collect()
+    }
+
+    /// The discovery keys of the feeds opened by the remote, in the order they were opened.
+    pub fn remote_feeds(&self) -> Vec<DiscoveryKey> {
+        self._remote_feeds
+            .iter()
+            .flatten()
+            .filter_map(|feed| feed.borrow().discovery_key.clone())
+            .collect()
+    }
+
+    /// A snapshot of the traffic on the connection so far.
+    pub fn stats(&self) -> Stats {
+        let counters = self.counters.borrow();
+        Stats {
+            bytes_sent: counters.bytes_sent,
+            bytes_received: counters.bytes_received,
+            sent: counters.sent,
+            received: counters.received,
+            open_feeds: self
+                ._local_feeds
+                .iter()
+                .filter(|feed| !feed.borrow().is_closed())
+                .count(),
+            buffered: if self._buf.is_some() {
+                self._pointer
+            } else {
+                0
+            },
+            handshake_latency: counters.handshake_latency,
+            feeds: self
+                ._feeds
+                .borrow()
+                .iter()
+                .map(|(dk, feed)| (dk.clone(), feed.borrow().stats()))
+                .collect(),
+        }
+    }
+
+    /// The extensions both sides have, sorted by name. Empty before the handshake.
+    pub fn negotiated_extensions(&self) -> Vec<String> {
+        let names = self.extensions.names();
+        let mut negotiated = self
+            .remote_extensions
+            .borrow()
+            .iter()
+            .flatten()
+            .map(|&id| names[id].clone())
+            .collect::<Vec<_>>();
+        negotiated.sort();
+        negotiated
     }
 
-    pub fn push(&mut self, bytes: &mut [u8]) {
-        self.stream.borrow_mut()._push(bytes);
+    /// Sends the payload of an extension on the connection, `false` before the remote
+    /// handshake or if the remote does not support it.
+    pub fn extension(&mut self, name: &str, payload: &[u8]) -> bool {
+        match self.extensions.id(name) {
+            Some(id) => FeedStreamHack::new(self)._connection_extension(id, payload),
+            None => false,
+        }
+    }
+
+    /// Encodes `message` with the registered connection extension `X` and sends it.
+    pub fn send_extension<X: ConnectionExtension>(&mut self, message: &X::Message) -> bool {
+        let (id, handler) = match self.extensions.find_connection::<X>() {
+            Some(found) => found,
+            None => return false,
+        };
+        let payload = match handler.borrow().as_any().downcast_ref::<X>() {
+            Some(extension) => extension.encode(message),
+            None => return false,
+        };
+        FeedStreamHack::new(self)._connection_extension(id, &payload)
+    }
+
+    /// Returns `false` if the bytes queued by the stream reached the high-water mark. Feeds get
+    /// a `FeedEvent::Drain` once enough of them is reported as flushed with `drain`.
+    pub fn push(&mut self, bytes: &mut [u8]) -> bool {
+        push_to_stream(
+            &self.stream,
+            bytes,
+            &self._queued,
+            &self._needs_drain,
+            self.high_water_mark,
+            &self.counters,
+        )
+    }
+
+    /// Tells the protocol that the stream flushed `bytes` bytes that it has queued before.
+    pub fn drain(&mut self, bytes: usize) {
+        trace!(self.log, "drain({}), queued: {}", bytes, self._queued.get());
+        let queued = self._queued.get().saturating_sub(bytes);
+        self._queued.set(queued);
+
+        if self._needs_drain.get() && queued < self.high_water_mark {
+            self._needs_drain.set(false);
+            for feed in &self._local_feeds {
+                feed.borrow_mut()._ondrain();
+            }
+        }
+    }
+
+    /// Number of bytes queued by the stream that are not reported as flushed yet.
+    pub fn buffered_amount(&self) -> usize {
+        self._queued.get()
+    }
+
+    /// Stops consuming input: `_write` returns 0 until `resume` is called.
+    pub fn pause(&mut self) {
+        trace!(self.log, "pause()");
+        self._paused = true;
+    }
+
+    /// Continues parsing, including any input that was held back while paused.
+    pub fn resume(&mut self) {
+        trace!(self.log, "resume()");
+        self._paused = false;
+        if !self._needs_key {
+            self._resume();
+        }
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self._paused
     }
 
     fn _resume(&mut self) {
@@ -419,10 +1198,14 @@ impl<E: FeedEventEmitter, S: Stream> Protocol<E, S> {
 
         trace!(
             self.log,
-            "_resume(): data: {:?} start:{}",
-            self._data,
-            self._start
+            "_resume(): data: {:?} start:{} paused: {}",
+            self._data.as_deref().map(Bytes),
+            self._start,
+            self._paused
         );
+        if self._paused {
+            return;
+        }
 
         if let Some(mut data) = self._data.take() {
             let start = self._start;
@@ -447,36 +1230,53 @@ impl<E: FeedEventEmitter, S: Stream> Protocol<E, S> {
         //        this.emit('close');
     }
 
-    fn _close(&mut self) {
-        //        clearInterval(this._interval);
+    /// Closes all feeds, emits `FeedEvent::End` and ends the stream. Should also be called when
+    /// the remote ended its side of the connection.
+    pub fn finalize(&mut self) {
+        FeedStreamHack::new(self)._finalize();
+    }
 
-        let feeds = std::mem::replace(&mut self._feeds, HashMap::new());
-        for (_, feed) in feeds {
-            feed.borrow_mut()._onclose();
-        }
+    fn _update_end(&mut self) {
+        FeedStreamHack::new(self)._update_end();
+    }
 
-        *self._xor.borrow_mut() = None;
+    fn _close(&mut self) {
+        //        clearInterval(this._interval);
+        FeedStreamHack::new(self)._close();
     }
 
-    pub fn _write(&mut self, bytes: &mut [u8]) {
+    /// Returns the number of bytes consumed, the rest has to be written again later.
+    ///
+    /// Nothing is consumed while the protocol is paused or while it is waiting for the key of
+    /// the first feed (the nodejs implementation holds back the write callback in both cases).
+    /// Once parsing started, the whole buffer is consumed, even if the parser stalls halfway.
+    pub fn _write(&mut self, bytes: &mut [u8]) -> usize {
+        if self._paused || self._data.is_some() {
+            trace!(self.log, "_write: not consuming input");
+            return 0;
+        }
         self._remote_keep_alive = 0;
-        self._parse(bytes, 0)
+        self.counters.borrow_mut().bytes_received += bytes.len() as u64;
+        #[cfg(feature = "metrics")]
+        metrics::counter!("hypercore_protocol_bytes_received").increment(bytes.len() as u64);
+        self._parse(bytes, 0);
+        self._update_end();
+        bytes.len()
     }
 
-    fn _feed(
-        &mut self,
-        dk: &DiscoveryKey,
-    ) -> Rc<RefCell<Feed<FeedStreamHack<E, S>, FeedEventEmitterImpl>>> {
-        if let Some(ch) = self._feeds.get_mut(dk) {
+    fn _feed(&mut self, dk: &DiscoveryKey) -> FeedRc<E, S> {
+        if let Some(ch) = self._feeds.borrow().get(dk) {
             return ch.clone();
         }
-        let ch = Feed::new(
-            self.log.clone(),
+        let mut ch = Feed::new(
+            self.log.feed(dk),
             FeedStreamHack::new(self),
             FeedEventEmitterImpl::new(self),
         );
-        self._feeds.insert(dk.clone(), Rc::new(RefCell::new(ch)));
-        self._feeds.get_mut(dk).unwrap().clone()
+        ch.discovery_key = Some(dk.clone());
+        let ch = Rc::new(RefCell::new(ch));
+        self._feeds.borrow_mut().insert(dk.clone(), ch.clone());
+        ch
     }
 
     fn _onopen(&mut self, id: Channel, bytes: &[u8], start: usize, end: usize) {
@@ -484,12 +1284,12 @@ impl<E: FeedEventEmitter, S: Stream> Protocol<E, S> {
             self.log,
             "onopen({:?}, {:?}, {}, {})",
             id,
-            bytes,
+            Bytes(bytes),
             start,
             end
         );
         let feed = decode_feed(&self.log, bytes, start, end);
-        trace!(self.log, "onopen: feed: {:?}", feed);
+        trace!(self.log, "onopen: feed: {:?}", Payload(&feed));
 
         let feed = match feed {
             Some(feed) => feed,
@@ -512,9 +1312,9 @@ impl<E: FeedEventEmitter, S: Stream> Protocol<E, S> {
 
             trace!(
                 self.log,
-                "onopen: encrypted: {}, remote_nonce: {:?}",
+                "onopen: encrypted: {}, remote_nonce: {}",
                 self.encrypted,
-                self._remote_nonce
+                self._remote_nonce.is_some()
             );
             if self.encrypted && self._remote_nonce.is_none() {
                 if !feed.has_nonce() {
@@ -526,9 +1326,9 @@ impl<E: FeedEventEmitter, S: Stream> Protocol<E, S> {
 
             trace!(
                 self.log,
-                "onopen: encrypted: {}, key: {:?}, remote_xor: {:?}",
+                "onopen: encrypted: {}, key: {}, remote_xor: {}",
                 self.encrypted,
-                self.key,
+                self.key.is_some(),
                 self._remote_xor.is_some()
             );
             if self.encrypted && self.key.is_some() && self._remote_xor.is_none() {
@@ -549,14 +1349,38 @@ impl<E: FeedEventEmitter, S: Stream> Protocol<E, S> {
             .as_ref()
             .unwrap()
             .borrow_mut()
-            .remote_id = Some(id);
+            .set_remote_id(id);
+
+        let opened = self._feeds.borrow()[&dk].borrow().id.is_some();
+        if let (false, Some(key_lookup)) = (opened, self.key_lookup.clone()) {
+            match key_lookup(&dk) {
+                Some(key) if key.discovery_key() == dk => {
+                    trace!(self.log, "onopen: opening feed from key lookup");
+                    let opts = FeedOptions {
+                        discovery_key: Some(dk),
+                        ..FeedOptions::default()
+                    };
+                    if let Err(err) = self.feed(&key, opts) {
+                        trace!(self.log, "onopen: opening feed failed: {}", err);
+                    }
+                }
+                Some(_) => trace!(self.log, "onopen: key lookup returned a different key"),
+                None => {}
+            }
+        }
 
         //        self.emit("feed", feed.discoveryKey);
     }
 
     fn _onmessage(&mut self, bytes: &[u8], mut start: usize, end: usize) {
         // TODO Use wire_format::read_msg for parsing the message
-        trace!(self.log, "_onmessage({:?}, {}, {})", bytes, start, end);
+        trace!(
+            self.log,
+            "_onmessage({:?}, {}, {})",
+            Bytes(bytes),
+            start,
+            end
+        );
         if end - start < 2 {
             return;
         }
@@ -568,15 +1392,17 @@ impl<E: FeedEventEmitter, S: Stream> Protocol<E, S> {
             Some(h) => h,
             None => return self.destroy(Some("Remote sent invalid header")),
         };
+        self.counters.borrow_mut().received.add(r#type);
+        #[cfg(feature = "metrics")]
+        metrics::counter!("hypercore_protocol_frames_received", "type" => r#type.name())
+            .increment(1);
 
-        // FIXME this is always false, use `Result` to handle errors
         if id.0 as usize >= self.max_feeds {
-            return self._too_many_feeds();
+            return self.destroy(Some("Remote opened too many feeds"));
         }
         if self._remote_feeds.len() <= id.0 as usize {
             self._remote_feeds.resize(id.0 as usize + 1, None);
         }
-        assert_eq!(self._remote_feeds.len(), id.0 as usize + 1);
         let ch = &mut self._remote_feeds[id.0 as usize];
 
         if r#type == MessageType::Feed {
@@ -587,9 +1413,11 @@ impl<E: FeedEventEmitter, S: Stream> Protocol<E, S> {
         }
 
         if let Some(ch) = ch {
-            trace!(self.log, "ch: {:?}", ch);
+            if let Some(ref dk) = ch.borrow().discovery_key {
+                trace!(self.log, "ch: {:?} ({}..)", id, fingerprint(&dk.0));
+            }
             if r#type == MessageType::Extension {
-                return ch.borrow()._onextension(bytes, start, end);
+                return ch.borrow_mut()._onextension(bytes, start, end);
             }
             ch.borrow_mut()._onmessage(r#type, bytes, start, end);
         } else {
@@ -598,7 +1426,7 @@ impl<E: FeedEventEmitter, S: Stream> Protocol<E, S> {
     }
 
     fn _parse(&mut self, mut bytes: &mut [u8], mut start: usize) {
-        trace!(self.log, "_parse({:?}, {})", bytes, start);
+        trace!(self.log, "_parse({:?}, {})", Bytes(bytes), start);
         let decrypted = self._remote_xor.is_some();
         trace!(self.log, "decrypted: {}", decrypted);
 
@@ -650,7 +1478,7 @@ impl<E: FeedEventEmitter, S: Stream> Protocol<E, S> {
     }
 
     fn _parse_message(&mut self, bytes: &[u8], mut start: usize) -> usize {
-        trace!(self.log, "_parse_message({:?}, {})", bytes, start);
+        trace!(self.log, "_parse_message({:?}, {})", Bytes(bytes), start);
         let mut end = start + self._missing as usize;
 
         if end <= bytes.len() {
@@ -721,6 +1549,10 @@ impl<E: FeedEventEmitter, S: Stream> Protocol<E, S> {
             trace!(self.log, "Same key: not encrypted");
             return true;
         }
+        if let ConnectionKey::Fixed(_) = self.connection_key {
+            trace!(self.log, "Same key: fixed connection key");
+            return true;
+        }
         trace!(
             self.log,
             "Same key: {:?}",
@@ -763,10 +1595,6 @@ impl<E: FeedEventEmitter, S: Stream> Protocol<E, S> {
         //        false
     }
 
-    fn _too_many_feeds(&self) -> ! {
-        unimplemented!()
-    }
-
     fn _too_big(&self, len: usize) -> ! {
         unimplemented!()
     }
@@ -780,22 +1608,59 @@ impl<E: FeedEventEmitter, S: Stream> Protocol<E, S> {
     }
 }
 
-pub struct FeedStreamHack<E: FeedEventEmitter, S: Stream> {
+type FeedRc<E, S> = Rc<RefCell<Feed<FeedStreamHack<E, S>, FeedEventEmitterImpl<E>>>>;
+type FeedMap<E, S> = HashMap<DiscoveryKey, FeedRc<E, S>>;
+
+pub(crate) struct FeedStreamHack<E: FeedEventEmitter, S: Stream> {
     stream: Rc<RefCell<S>>,
     emitter: Rc<RefCell<E>>,
 
-    extensions: Rc<RefCell<Vec<String>>>,
+    extensions: Rc<Extensions>,
 
-    remote_id: Rc<RefCell<Option<Id>>>,
-    remote_live: Rc<Cell<Option<bool>>>,
-    remote_ack: Rc<Cell<Option<bool>>>,
-    remote_user_data: Rc<RefCell<Option<Vec<u8>>>>,
+    remote: Rc<RefCell<Option<RemoteInfo>>>,
+    ack: bool,
     remote_extensions: Rc<RefCell<Vec<Option<usize>>>>,
+    handshake_channel: Rc<Cell<Option<Channel>>>,
+    counters: Rc<RefCell<Counters>>,
+    rng: Option<Rng>,
 
     destroyed: Rc<Cell<bool>>,
+    live: bool,
+    // Weak, the feeds own their stream
+    feeds: Weak<RefCell<FeedMap<E, S>>>,
 
     _xor: Rc<RefCell<Option<Xor>>>,
     _keep_alive: Rc<Cell<u8>>,
+    high_water_mark: usize,
+    _queued: Rc<Cell<usize>>,
+    _needs_drain: Rc<Cell<bool>>,
+}
+impl<E: FeedEventEmitter, S: Stream> Clone for FeedStreamHack<E, S> {
+    fn clone(&self) -> Self {
+        FeedStreamHack {
+            stream: self.stream.clone(),
+            emitter: self.emitter.clone(),
+
+            extensions: self.extensions.clone(),
+
+            remote: self.remote.clone(),
+            ack: self.ack,
+            remote_extensions: self.remote_extensions.clone(),
+            handshake_channel: self.handshake_channel.clone(),
+            counters: self.counters.clone(),
+            rng: self.rng.clone(),
+
+            destroyed: self.destroyed.clone(),
+            live: self.live,
+            feeds: self.feeds.clone(),
+
+            _xor: self._xor.clone(),
+            _keep_alive: self._keep_alive.clone(),
+            high_water_mark: self.high_water_mark,
+            _queued: self._queued.clone(),
+            _needs_drain: self._needs_drain.clone(),
+        }
+    }
 }
 impl<E: FeedEventEmitter, S: Stream> FeedStreamHack<E, S> {
     fn new(protocol: &Protocol<E, S>) -> Self {
@@ -805,66 +1670,198 @@ impl<E: FeedEventEmitter, S: Stream> FeedStreamHack<E, S> {
 
             extensions: protocol.extensions.clone(),
 
-            remote_id: protocol.remote_id.clone(),
-            remote_live: protocol.remote_live.clone(),
-            remote_ack: protocol.remote_ack.clone(),
-            remote_user_data: protocol.remote_user_data.clone(),
+            remote: protocol.remote.clone(),
+            ack: protocol.ack,
             remote_extensions: protocol.remote_extensions.clone(),
+            handshake_channel: protocol.handshake_channel.clone(),
+            counters: protocol.counters.clone(),
+            rng: protocol.rng.clone(),
 
             destroyed: protocol.destroyed.clone(),
+            live: protocol.live,
+            feeds: Rc::downgrade(&protocol._feeds),
 
             _xor: protocol._xor.clone(),
             _keep_alive: protocol._keep_alive.clone(),
+            high_water_mark: protocol.high_water_mark,
+            _queued: protocol._queued.clone(),
+            _needs_drain: protocol._needs_drain.clone(),
+        }
+    }
+
+    fn _finalize(&self) {
+        if self.destroyed.get() {
+            return;
         }
+        self.destroyed.set(true);
+        self._close();
+        self.emitter.borrow_mut().emit(FeedEvent::End);
+        self.stream.borrow_mut()._end();
+    }
+
+    /// Finalizes if neither side is live and neither side is downloading any of the feeds that
+    /// are open locally.
+    fn _update_end(&self) {
+        let remote_live = match *self.remote.borrow() {
+            Some(ref remote) => remote.live,
+            None => return,
+        };
+        if self.destroyed.get() || self.live || remote_live {
+            return;
+        }
+        let finished = match self.feeds.upgrade() {
+            Some(feeds) => {
+                let feeds = feeds.borrow();
+                let open = feeds
+                    .values()
+                    .filter(|feed| {
+                        let feed = feed.borrow();
+                        feed.id.is_some() && !feed.is_closed()
+                    })
+                    .collect::<Vec<_>>();
+                !open.is_empty() && open.iter().all(|feed| feed.borrow().is_finished())
+            }
+            None => false,
+        };
+        if finished {
+            self._finalize();
+        }
+    }
+
+    fn _close(&self) {
+        if let Some(feeds) = self.feeds.upgrade() {
+            let feeds = mem::take(&mut *feeds.borrow_mut());
+            for (_, feed) in feeds {
+                feed.borrow_mut()._onclose();
+            }
+        }
+
+        *self._xor.borrow_mut() = None;
     }
 }
 impl<E: FeedEventEmitter, S: Stream> FeedStream for FeedStreamHack<E, S> {
-    fn _push(&mut self, bytes: &[u8]) {
-        log::trace!("FeedStreamHack::_push({:?})", bytes);
+    fn _push(&mut self, bytes: &[u8]) -> bool {
+        global_trace!("FeedStreamHack::_push({:?})", Bytes(bytes));
         if self.destroyed.get() {
-            return;
+            return false;
         }
         self._keep_alive.set(0);
+        if let Some(r#type) = wire_format::peek_message_type(bytes) {
+            self.counters.borrow_mut().sent.add(r#type);
+            #[cfg(feature = "metrics")]
+            metrics::counter!("hypercore_protocol_frames_sent", "type" => r#type.name())
+                .increment(1);
+        }
 
-        let mut buf = vec![0u8; bytes.len()];
+        let mut buf = bytes.to_vec();
         if let Some(xor) = self._xor.borrow_mut().as_mut() {
             xor.update(bytes, &mut buf);
         }
 
-        self.stream.borrow_mut()._push(&mut buf);
+        push_to_stream(
+            &self.stream,
+            &mut buf,
+            &self._queued,
+            &self._needs_drain,
+            self.high_water_mark,
+            &self.counters,
+        )
+    }
+
+    fn _ack(&self) -> bool {
+        self.ack
+            && self
+                .remote
+                .borrow()
+                .as_ref()
+                .is_some_and(|remote| remote.ack)
+    }
+
+    fn _extensions(&self) -> Rc<Extensions> {
+        self.extensions.clone()
+    }
+
+    fn _local_extension(&self, remote_id: u64) -> Option<usize> {
+        let remote_extensions = self.remote_extensions.borrow();
+        usize::try_from(remote_id)
+            .ok()
+            .and_then(|remote_id| remote_extensions.get(remote_id).cloned().flatten())
+    }
+
+    fn _remote_supports(&self, id: usize) -> bool {
+        self.remote_extensions.borrow().contains(&Some(id))
+    }
+
+    fn _connection_extension(&mut self, id: usize, payload: &[u8]) -> bool {
+        let channel = match self.handshake_channel.get() {
+            Some(channel) => channel,
+            None => return false,
+        };
+        if !self._remote_supports(id) {
+            return false;
+        }
+        let message = wire_format::extension_message(id, payload);
+        self._push(&wire_format::write_msg(channel, &message).unwrap())
     }
 
     fn _onhandshake(&mut self, hs: &schema::Handshake) {
-        log::trace!("FeedStreamHack::_onhandshake({:?})", hs);
-        if self.remote_id.borrow().is_some() {
+        global_trace!("FeedStreamHack::_onhandshake({:?})", Payload(hs));
+        if self.remote.borrow().is_some() {
             return;
         }
 
-        *self.remote_id.borrow_mut() = Some(if hs.has_id() {
-            hs.get_id().try_into().unwrap()
-        } else {
-            random_id()
-        });
-        self.remote_live.set(if hs.has_live() {
-            Some(hs.get_live())
-        } else {
-            None
-        });
-        *self.remote_user_data.borrow_mut() = if hs.has_userData() {
-            Some(hs.get_userData().into())
-        } else {
-            None
+        let rng = &self.rng;
+        let remote = match RemoteInfo::from_handshake(hs, || random_id(rng)) {
+            Ok(remote) => remote,
+            Err(()) => return self._destroy("Remote sent an invalid id"),
         };
+        *self.remote.borrow_mut() = Some(remote);
+        let mut counters = self.counters.borrow_mut();
+        counters.handshake_latency = counters.handshake_sent.map(|sent| sent.elapsed());
+        #[cfg(feature = "metrics")]
+        if let Some(latency) = counters.handshake_latency {
+            metrics::histogram!("hypercore_protocol_handshake_latency_seconds")
+                .record(latency.as_secs_f64());
+        }
+        drop(counters);
         *self.remote_extensions.borrow_mut() =
-            sorted_index_of(&self.extensions.borrow(), hs.get_extensions());
-        self.remote_ack.set(if hs.has_ack() {
-            Some(hs.get_ack())
-        } else {
-            None
-        });
+            sorted_index_of(self.extensions.names(), hs.get_extensions());
 
         self.emitter.borrow_mut().emit(FeedEvent::Handshake);
     }
+
+    fn _destroy(&mut self, err: &str) {
+        global_trace!("FeedStreamHack::_destroy({:?})", err);
+        if self.destroyed.get() {
+            return;
+        }
+        self.destroyed.set(true);
+        *self._xor.borrow_mut() = None;
+    }
+}
+
+fn push_to_stream<S: Stream>(
+    stream: &RefCell<S>,
+    bytes: &mut [u8],
+    queued: &Cell<usize>,
+    needs_drain: &Cell<bool>,
+    high_water_mark: usize,
+    counters: &RefCell<Counters>,
+) -> bool {
+    let len = bytes.len();
+    counters.borrow_mut().bytes_sent += len as u64;
+    #[cfg(feature = "metrics")]
+    metrics::counter!("hypercore_protocol_bytes_sent").increment(len as u64);
+    if stream.borrow_mut()._push(bytes) == Push::WouldBlock {
+        queued.set(queued.get() + len);
+    }
+
+    if queued.get() >= high_water_mark {
+        needs_drain.set(true);
+        false
+    } else {
+        true
+    }
 }
 
 // https://github.com/mafintosh/sorted-indexof
@@ -875,26 +1872,26 @@ fn sorted_index_of<T: Ord>(haystack: &[T], needles: &[T]) -> Vec<Option<usize>>
         .collect()
 }
 
-pub struct FeedEventEmitterImpl;
-impl FeedEventEmitterImpl {
-    fn new<E: FeedEventEmitter, S: Stream>(protocol: &Protocol<E, S>) -> Self {
-        FeedEventEmitterImpl
+pub(crate) struct FeedEventEmitterImpl<E: FeedEventEmitter>(Rc<RefCell<E>>);
+impl<E: FeedEventEmitter> FeedEventEmitterImpl<E> {
+    fn new<S: Stream>(protocol: &Protocol<E, S>) -> Self {
+        FeedEventEmitterImpl(protocol.emitter.clone())
     }
 }
-impl FeedEventEmitter for FeedEventEmitterImpl {
+impl<E: FeedEventEmitter> FeedEventEmitter for FeedEventEmitterImpl<E> {
     fn emit(&mut self, event: FeedEvent) {
-        unimplemented!()
+        self.0.borrow_mut().emit(event);
     }
 }
 
-fn random_id() -> Id {
+fn random_id(rng: &Option<Rng>) -> Id {
     let mut id = [0u8; 32];
-    random_bytes_into(&mut id);
+    fill_random(rng, &mut id);
     Id(id)
 }
 
-fn decode_header(log: &Logger, bytes: &[u8], start: &mut usize) -> Option<Header> {
-    trace!(log, "decode_header {:?} {:?}", bytes, start);
+fn decode_header(log: &Log, bytes: &[u8], start: &mut usize) -> Option<Header> {
+    trace!(log, "decode_header {:?} {:?}", Bytes(bytes), start);
     let (value, read_bytes) = VarInt::decode_var(&bytes[*start..]);
 
     // Why is 0xffff an error?
@@ -908,10 +1905,10 @@ fn decode_header(log: &Logger, bytes: &[u8], start: &mut usize) -> Option<Header
     result
 }
 
-fn decode_feed(log: &Logger, bytes: &[u8], start: usize, end: usize) -> Option<schema::Feed> {
-    trace!(log, "decode_feed {:?} {:?} {:?}", bytes, start, end);
+fn decode_feed(log: &Log, bytes: &[u8], start: usize, end: usize) -> Option<schema::Feed> {
+    trace!(log, "decode_feed {:?} {:?} {:?}", Bytes(bytes), start, end);
     let feed = parse_from_bytes::<schema::Feed>(&bytes[start..end]).ok()?;
-    trace!(log, "decode_feed feed: {:?}", feed);
+    trace!(log, "decode_feed feed: {:?}", Payload(&feed));
     let invalid_dk = feed.get_discoveryKey().len() != 32;
     let invalid_nonce = feed.has_nonce() && feed.get_nonce().len() != 24;
     let result = if invalid_dk || invalid_nonce {
@@ -919,7 +1916,7 @@ fn decode_feed(log: &Logger, bytes: &[u8], start: usize, end: usize) -> Option<s
     } else {
         Some(feed)
     };
-    trace!(log, "decode_feed -> {:?}", result);
+    trace!(log, "decode_feed -> {:?}", Payload(&result));
     result
 }
 
@@ -936,10 +1933,11 @@ fn discovery_key(key: &[u8]) -> DiscoveryKey {
     result
 }
 
-fn random_bytes(n: usize) -> Vec<u8> {
-    let mut buf = vec![0u8; n];
-    random_bytes_into(&mut buf);
-    buf
+fn fill_random(rng: &Option<Rng>, buf: &mut [u8]) {
+    match rng {
+        Some(rng) => rng(buf),
+        None => random_bytes_into(buf),
+    }
 }
 
 const NOT_RANDOM_BYTES: Option<[u8; 1024]> = None;
@@ -958,6 +1956,8 @@ fn random_bytes_into(buf: &mut [u8]) {
 mod tests {
     use data_encoding::HEXUPPER;
 
+    use crate::extension::ExtensionFeed;
+
     use super::*;
 
     #[test]
@@ -968,6 +1968,133 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_key_discovery_key() {
+        let key = Key(*b"01234567890123456789012345678901");
+        assert_eq!(
+            key.discovery_key().to_string(),
+            "103e9c9562455f70dfe3f3f9f1dc0cf8548d72d6c4b3c5ac1b44eaefdb6f7e65"
+        );
+        assert_eq!(key.discovery_key().as_bytes(), &discovery_key(&key.0).0);
+    }
+
+    #[test]
+    fn test_redacted_debug() {
+        let key = Key(*b"01234567890123456789012345678901");
+        assert_eq!(format!("{:?}", key), "Key(103e9c95..)");
+        assert_eq!(
+            format!("{:?}", ConnectionKey::Fixed(key.clone())),
+            "Fixed(Key(103e9c95..))"
+        );
+        assert!(key.to_string().starts_with("3031"));
+
+        let id = Id(key.0);
+        assert!(format!("{:?}", id).starts_with("Id("));
+        assert!(!format!("{:?}", id).contains("3031"));
+        assert_eq!(id.to_string(), key.to_string());
+
+        assert_eq!(format!("{:?}", Nonce([1; 24])), "Nonce(..)");
+
+        let builder = ProtocolBuilder::new().user_data(b"secret".to_vec());
+        assert_eq!(
+            format!("{:?}", builder).contains("<6 bytes>"),
+            !cfg!(feature = "log-payloads")
+        );
+    }
+
+    #[test]
+    fn test_zbase32() {
+        assert_eq!(zbase32().encode(b"\xf0\xbf\xc7"), "6n9hq");
+        assert_eq!(zbase32().encode(b"\xd4\x7a\x04"), "4t7ye");
+    }
+
+    #[test]
+    fn test_key_format() {
+        let dk = Key(*b"01234567890123456789012345678901").discovery_key();
+        let hex = "103E9C9562455F70DFE3F3F9F1DC0CF8548D72D6C4B3C5AC1B44EAEFDB6F7E65";
+        assert_eq!(hex.parse(), Ok(dk.clone()));
+        assert_eq!(dk.to_string().parse(), Ok(dk.clone()));
+
+        let z32 = format!("{:#}", dk);
+        assert_eq!(z32.len(), 52);
+        assert_eq!(z32.parse(), Ok(dk.clone()));
+        assert_eq!(z32.parse::<Id>().map(|id| id.0), Ok(dk.0));
+
+        assert_eq!("".parse::<Key>(), Err(InvalidKey));
+        assert_eq!(hex[2..].parse::<Key>(), Err(InvalidKey));
+        assert_eq!(z32.replace('y', "l").parse::<Key>(), Err(InvalidKey));
+    }
+
+    struct NullEmitter;
+    impl FeedEventEmitter for NullEmitter {
+        fn emit(&mut self, _event: FeedEvent) {}
+    }
+
+    struct NullStream;
+    impl Stream for NullStream {
+        fn _push(&mut self, _bytes: &mut [u8]) -> Push {
+            Push::Accepted
+        }
+    }
+
+    struct TestExtension;
+    impl Extension for TestExtension {
+        type Message = ();
+
+        fn name(&self) -> &str {
+            "test"
+        }
+
+        fn encode(&self, _message: &()) -> Vec<u8> {
+            Vec::new()
+        }
+
+        fn decode(&self, _payload: &[u8]) -> Option<()> {
+            Some(())
+        }
+
+        fn on_message(&mut self, _feed: &mut dyn ExtensionFeed, _message: ()) {}
+    }
+
+    #[test]
+    fn test_builder() {
+        let protocol = ProtocolBuilder::new()
+            .extensions(vec!["b".to_owned(), "c".to_owned()])
+            .extension("a")
+            .extension("b")
+            .rng(|buf| buf.iter_mut().for_each(|b| *b = 42))
+            .build(NullEmitter, NullStream)
+            .unwrap();
+        assert_eq!(protocol.extensions.names(), ["a", "b", "c"]);
+        assert_eq!(protocol.id(), &Id([42; 32]));
+
+        let build = |builder: ProtocolBuilder| builder.build(NullEmitter, NullStream).err();
+        assert_eq!(
+            build(ProtocolBuilder::new().extension("")),
+            Some(BuildError::EmptyExtension)
+        );
+        assert_eq!(
+            build(
+                ProtocolBuilder::new()
+                    .register(TestExtension)
+                    .register(TestExtension)
+            ),
+            Some(BuildError::DuplicateExtension("test".to_owned()))
+        );
+        assert_eq!(
+            build(ProtocolBuilder::new().max_feeds(257)),
+            Some(BuildError::InvalidMaxFeeds(257))
+        );
+        assert_eq!(
+            build(ProtocolBuilder::new().high_water_mark(0)),
+            Some(BuildError::InvalidHighWaterMark)
+        );
+        assert_eq!(
+            build(ProtocolBuilder::new().request_timeout(Duration::from_secs(0))),
+            Some(BuildError::InvalidRequestTimeout)
+        );
+    }
+
     #[test]
     fn test_encode_feed() {
         let mut feed = schema::Feed::new();
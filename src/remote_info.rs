@@ -0,0 +1,125 @@
+use std::convert::TryInto;
+
+use crate::protocol::Id;
+use crate::schema;
+
+/// What the remote sent in its handshake, see `Protocol::remote`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct RemoteInfo {
+    /// Random if the remote did not send one.
+    pub id: Id,
+    pub live: bool,
+    pub ack: bool,
+    pub user_data: Option<Vec<u8>>,
+    /// All the extensions of the remote, including the ones not supported locally.
+    pub extensions: Vec<String>,
+}
+
+impl RemoteInfo {
+    /// Fails if the id has the wrong length.
+    pub(crate) fn from_handshake(
+        handshake: &schema::Handshake,
+        random_id: impl FnOnce() -> Id,
+    ) -> Result<Self, ()> {
+        Ok(RemoteInfo {
+            id: if handshake.has_id() {
+                handshake.get_id().try_into()?
+            } else {
+                random_id()
+            },
+            live: handshake.get_live(),
+            ack: handshake.get_ack(),
+            user_data: if handshake.has_userData() {
+                Some(handshake.get_userData().into())
+            } else {
+                None
+            },
+            extensions: handshake.get_extensions().into(),
+        })
+    }
+
+    /// Decodes the user data as JSON, `None` if there is none.
+    #[cfg(feature = "json-user-data")]
+    pub fn user_data_json<T: serde::de::DeserializeOwned>(&self) -> Option<serde_json::Result<T>> {
+        self.user_data
+            .as_ref()
+            .map(|user_data| serde_json::from_slice(user_data))
+    }
+
+    /// Decodes the user data as a protobuf message, `None` if there is none.
+    #[cfg(feature = "protobuf-user-data")]
+    pub fn user_data_protobuf<M: protobuf::Message>(&self) -> Option<protobuf::ProtobufResult<M>> {
+        self.user_data
+            .as_ref()
+            .map(|user_data| M::parse_from_bytes(user_data))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn remote_info(user_data: &[u8]) -> RemoteInfo {
+        let mut handshake = schema::Handshake::new();
+        handshake.set_live(true);
+        handshake.set_userData(user_data.into());
+        handshake.set_extensions(["a".to_owned()][..].into());
+        RemoteInfo::from_handshake(&handshake, || Id([1; 32])).unwrap()
+    }
+
+    #[test]
+    fn test_from_handshake() {
+        assert_eq!(
+            remote_info(b"data"),
+            RemoteInfo {
+                id: Id([1; 32]),
+                live: true,
+                ack: false,
+                user_data: Some(b"data".to_vec()),
+                extensions: vec!["a".to_owned()],
+            }
+        );
+    }
+
+    #[test]
+    fn test_invalid_id() {
+        let mut handshake = schema::Handshake::new();
+        handshake.set_id(vec![1; 31]);
+        assert_eq!(
+            RemoteInfo::from_handshake(&handshake, || Id([1; 32])),
+            Err(())
+        );
+        handshake.set_id(vec![2; 32]);
+        assert_eq!(
+            RemoteInfo::from_handshake(&handshake, || Id([1; 32])).map(|remote| remote.id),
+            Ok(Id([2; 32]))
+        );
+    }
+
+    #[cfg(feature = "json-user-data")]
+    #[test]
+    fn test_user_data_json() {
+        let remote = remote_info(br#"{"name": "peer"}"#);
+        let user_data: serde_json::Value = remote.user_data_json().unwrap().unwrap();
+        assert_eq!(user_data["name"], "peer");
+        assert!(remote_info(b"{")
+            .user_data_json::<serde_json::Value>()
+            .unwrap()
+            .is_err());
+    }
+
+    #[cfg(feature = "protobuf-user-data")]
+    #[test]
+    fn test_user_data_protobuf() {
+        let mut info = schema::Info::new();
+        info.set_uploading(true);
+        let remote = remote_info(&protobuf::Message::write_to_bytes(&info).unwrap());
+        assert_eq!(
+            remote
+                .user_data_protobuf::<schema::Info>()
+                .unwrap()
+                .unwrap(),
+            info
+        );
+    }
+}
@@ -0,0 +1,357 @@
+use std::cmp::{max, min};
+use std::fmt::{self, Display, Formatter};
+use std::ops::Range;
+
+use crate::bitfield::Bitfield;
+use crate::bitfield_rle;
+use crate::schema;
+
+/// Upper bound for the block indices a remote can announce. It bounds the memory a remote can
+/// make us allocate for its bitfield to 128 MiB.
+pub const MAX_BLOCKS: u64 = 1 << 30;
+
+/// Upper bound for the blocks a single `Have` can cover, 2 MiB of bitfield.
+pub const MAX_HAVE_BLOCKS: u64 = 1 << 24;
+
+/// Upper bound for the separate ranges a remote can want at once.
+pub const MAX_WANTS: usize = 1024;
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum RemoteStateError {
+    InvalidBitfield,
+    TooManyBlocks,
+    TooManyWants,
+}
+
+impl Display for RemoteStateError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            RemoteStateError::InvalidBitfield => write!(f, "Remote sent an invalid bitfield"),
+            RemoteStateError::TooManyBlocks => write!(f, "Remote announced too many blocks"),
+            RemoteStateError::TooManyWants => write!(f, "Remote wants too many ranges"),
+        }
+    }
+}
+
+/// What the remote end of a feed has and wants, as announced in its `Have`, `Unhave`, `Want`
+/// and `Unwant` messages.
+#[derive(Clone, Debug, Default)]
+pub struct RemoteState {
+    bitfield: Bitfield,
+    wants: Vec<Range<u64>>,
+}
+
+impl RemoteState {
+    pub fn new() -> Self {
+        RemoteState::default()
+    }
+
+    pub fn on_have(&mut self, have: &schema::Have) -> Result<(), RemoteStateError> {
+        let start = have.get_start();
+        if start > MAX_BLOCKS {
+            return Err(RemoteStateError::TooManyBlocks);
+        }
+
+        if have.has_bitfield() {
+            let max_bytes = (min(MAX_BLOCKS - start, MAX_HAVE_BLOCKS) / 8) as usize;
+            let bytes = bitfield_rle::decode(have.get_bitfield(), max_bytes)
+                .map_err(|_| RemoteStateError::InvalidBitfield)?;
+            self.bitfield.set_bytes(start, &bytes);
+        } else {
+            let length = have.get_length();
+            if length > min(MAX_BLOCKS - start, MAX_HAVE_BLOCKS) {
+                return Err(RemoteStateError::TooManyBlocks);
+            }
+            self.bitfield.set_range(start, length, true);
+        }
+        Ok(())
+    }
+
+    pub fn on_unhave(&mut self, unhave: &schema::Unhave) {
+        // Nothing to clear beyond the last block the remote has
+        let start = unhave.get_start();
+        let end = min(
+            start.saturating_add(unhave.get_length()),
+            self.remote_length(),
+        );
+        if start < end {
+            self.bitfield.set_range(start, end - start, false);
+        }
+    }
+
+    pub fn on_want(&mut self, want: &schema::Want) -> Result<(), RemoteStateError> {
+        let length = if want.has_length() {
+            Some(want.get_length())
+        } else {
+            None
+        };
+        add_range(&mut self.wants, want_range(want.get_start(), length));
+        self.check_wants()
+    }
+
+    /// Can split a range in two, so it is limited like `on_want`.
+    pub fn on_unwant(&mut self, unwant: &schema::Unwant) -> Result<(), RemoteStateError> {
+        let length = if unwant.has_length() {
+            Some(unwant.get_length())
+        } else {
+            None
+        };
+        remove_range(&mut self.wants, want_range(unwant.get_start(), length));
+        self.check_wants()
+    }
+
+    fn check_wants(&self) -> Result<(), RemoteStateError> {
+        if self.wants.len() > MAX_WANTS {
+            return Err(RemoteStateError::TooManyWants);
+        }
+        Ok(())
+    }
+
+    pub fn remote_has(&self, index: u64) -> bool {
+        self.bitfield.get(index)
+    }
+
+    pub fn remote_wants(&self, index: u64) -> bool {
+        self.wants.iter().any(|range| range.contains(&index))
+    }
+
+    /// The sorted, non-overlapping ranges the remote wants. Ranges without an end (i.e. "until
+    /// the end of the feed") end at `u64::MAX`.
+    pub fn wants(&self) -> &[Range<u64>] {
+        &self.wants
+    }
+
+    /// The index after the last block the remote has.
+    pub fn remote_length(&self) -> u64 {
+        self.bitfield.length()
+    }
+
+    pub fn bitfield(&self) -> &Bitfield {
+        &self.bitfield
+    }
+
+    /// Ranges of blocks the remote has, but are not set in `local`.
+    pub fn missing<'a>(&'a self, local: &'a Bitfield) -> Missing<'a> {
+        Missing {
+            remote: &self.bitfield,
+            local,
+            index: 0,
+            end: self.remote_length(),
+        }
+    }
+}
+
+pub struct Missing<'a> {
+    remote: &'a Bitfield,
+    local: &'a Bitfield,
+    index: u64,
+    end: u64,
+}
+
+impl<'a> Missing<'a> {
+    fn is_missing(&self, index: u64) -> bool {
+        self.remote.get(index) && !self.local.get(index)
+    }
+}
+
+impl<'a> Iterator for Missing<'a> {
+    type Item = Range<u64>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while self.index < self.end && !self.is_missing(self.index) {
+            self.index += 1;
+        }
+        if self.index >= self.end {
+            return None;
+        }
+
+        let start = self.index;
+        while self.index < self.end && self.is_missing(self.index) {
+            self.index += 1;
+        }
+        Some(start..self.index)
+    }
+}
+
+// `length` defaults to infinity (the nodejs implementation uses feed.length if not live)
+fn want_range(start: u64, length: Option<u64>) -> Range<u64> {
+    match length {
+        Some(length) => start..start.saturating_add(length),
+        None => start..u64::MAX,
+    }
+}
+
+fn add_range(ranges: &mut Vec<Range<u64>>, range: Range<u64>) {
+    if range.start >= range.end {
+        return;
+    }
+
+    let mut merged = range;
+    ranges.retain(|r| {
+        if r.start <= merged.end && merged.start <= r.end {
+            merged = min(r.start, merged.start)..max(r.end, merged.end);
+            false
+        } else {
+            true
+        }
+    });
+    let position = ranges
+        .iter()
+        .position(|r| r.start > merged.start)
+        .unwrap_or(ranges.len());
+    ranges.insert(position, merged);
+}
+
+fn remove_range(ranges: &mut Vec<Range<u64>>, range: Range<u64>) {
+    let mut result = Vec::with_capacity(ranges.len() + 1);
+    for r in ranges.drain(..) {
+        if r.end <= range.start || range.end <= r.start {
+            result.push(r);
+            continue;
+        }
+        if r.start < range.start {
+            result.push(r.start..range.start);
+        }
+        if range.end < r.end {
+            result.push(range.end..r.end);
+        }
+    }
+    *ranges = result;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn have(start: u64, length: u64) -> schema::Have {
+        let mut have = schema::Have::new();
+        have.set_start(start);
+        have.set_length(length);
+        have
+    }
+
+    #[test]
+    fn test_have_and_unhave() {
+        let mut state = RemoteState::new();
+        state.on_have(&have(2, 3)).unwrap();
+        assert!(!state.remote_has(1));
+        assert!(state.remote_has(2));
+        assert!(state.remote_has(4));
+        assert!(!state.remote_has(5));
+        assert_eq!(state.remote_length(), 5);
+
+        let mut unhave = schema::Unhave::new();
+        unhave.set_start(3);
+        state.on_unhave(&unhave);
+        assert!(state.remote_has(2));
+        assert!(!state.remote_has(3));
+        assert!(state.remote_has(4));
+
+        assert_eq!(
+            state.on_have(&have(MAX_BLOCKS, 1)),
+            Err(RemoteStateError::TooManyBlocks)
+        );
+        assert_eq!(
+            state.on_have(&have(0, MAX_HAVE_BLOCKS + 1)),
+            Err(RemoteStateError::TooManyBlocks)
+        );
+    }
+
+    #[test]
+    fn test_have_bitfield() {
+        let mut state = RemoteState::new();
+        state.on_have(&have(0, 20)).unwrap();
+
+        // 1 byte of 0xff, then 0b1010_0000 literally
+        let mut bitfield_have = schema::Have::new();
+        bitfield_have.set_start(8);
+        bitfield_have.set_bitfield(vec![0b0000_0111, 0b0000_0010, 0b1010_0000]);
+        state.on_have(&bitfield_have).unwrap();
+
+        let has = (0..24).filter(|i| state.remote_has(*i)).collect::<Vec<_>>();
+        let expected = (0..8).chain(8..16).chain(vec![16, 18]).collect::<Vec<_>>();
+        assert_eq!(has, expected);
+
+        bitfield_have.set_bitfield(vec![0b0000_0010]);
+        assert_eq!(
+            state.on_have(&bitfield_have),
+            Err(RemoteStateError::InvalidBitfield)
+        );
+    }
+
+    #[test]
+    fn test_want_and_unwant() {
+        let mut state = RemoteState::new();
+        let mut want = schema::Want::new();
+        want.set_start(10);
+        want.set_length(10);
+        state.on_want(&want).unwrap();
+        want.set_start(15);
+        want.set_length(10);
+        state.on_want(&want).unwrap();
+        assert_eq!(state.wants().len(), 1);
+        assert_eq!(state.wants()[0], 10..25);
+
+        let mut unwant = schema::Unwant::new();
+        unwant.set_start(12);
+        unwant.set_length(2);
+        state.on_unwant(&unwant).unwrap();
+        assert_eq!(state.wants(), &[10..12, 14..25]);
+        assert!(state.remote_wants(11));
+        assert!(!state.remote_wants(12));
+
+        let mut want = schema::Want::new();
+        want.set_start(100);
+        state.on_want(&want).unwrap();
+        assert_eq!(state.wants(), &[10..12, 14..25, 100..u64::MAX]);
+        assert!(state.remote_wants(1 << 40));
+    }
+
+    #[test]
+    fn test_too_many_wants() {
+        let mut state = RemoteState::new();
+        let mut want = schema::Want::new();
+        want.set_length(1);
+        for i in 0..MAX_WANTS as u64 {
+            want.set_start(2 * i);
+            state.on_want(&want).unwrap();
+        }
+        // Overlapping ranges are merged
+        want.set_start(0);
+        state.on_want(&want).unwrap();
+        want.set_start(2 * MAX_WANTS as u64);
+        assert_eq!(state.on_want(&want), Err(RemoteStateError::TooManyWants));
+
+        let mut state = RemoteState::new();
+        want.set_start(0);
+        want.clear_length();
+        state.on_want(&want).unwrap();
+        let mut unwant = schema::Unwant::new();
+        unwant.set_length(1);
+        for i in 0..MAX_WANTS as u64 - 1 {
+            unwant.set_start(2 * i + 1);
+            state.on_unwant(&unwant).unwrap();
+        }
+        unwant.set_start(2 * MAX_WANTS as u64);
+        assert_eq!(
+            state.on_unwant(&unwant),
+            Err(RemoteStateError::TooManyWants)
+        );
+    }
+
+    #[test]
+    fn test_missing() {
+        let mut state = RemoteState::new();
+        state.on_have(&have(0, 10)).unwrap();
+        state.on_have(&have(12, 2)).unwrap();
+
+        let mut local = Bitfield::new();
+        local.set_range(2, 3, true);
+        local.set(13, true);
+
+        assert_eq!(
+            state.missing(&local).collect::<Vec<_>>(),
+            vec![0..2, 5..10, 12..13]
+        );
+    }
+}
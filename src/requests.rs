@@ -0,0 +1,170 @@
+use std::fmt::{self, Display, Formatter};
+use std::time::{Duration, Instant};
+
+use crate::schema;
+
+// Same as the default of `maxRequests` in hypercore
+pub const DEFAULT_MAX_REQUESTS: usize = 16;
+pub const DEFAULT_REQUEST_TIMEOUT: Duration = Duration::from_secs(20);
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum RequestError {
+    TooManyRequests,
+    AlreadyRequested,
+}
+
+impl Display for RequestError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            RequestError::TooManyRequests => write!(f, "Too many requests in flight"),
+            RequestError::AlreadyRequested => write!(f, "Block is already requested"),
+        }
+    }
+}
+
+/// Outstanding `Request`s of a feed, matched against incoming `Data` by index.
+///
+/// There is no timer in here, the caller passes in the current time instead.
+#[derive(Clone, Debug)]
+pub struct Requests {
+    max_requests: usize,
+    timeout: Duration,
+    pending: Vec<(schema::Request, Instant)>,
+}
+
+impl Default for Requests {
+    fn default() -> Self {
+        Requests::new(DEFAULT_MAX_REQUESTS, DEFAULT_REQUEST_TIMEOUT)
+    }
+}
+
+impl Requests {
+    pub fn new(max_requests: usize, timeout: Duration) -> Self {
+        Requests {
+            max_requests,
+            timeout,
+            pending: Vec::new(),
+        }
+    }
+
+    pub fn add(&mut self, request: schema::Request, now: Instant) -> Result<(), RequestError> {
+        if self.contains(request.get_index()) {
+            return Err(RequestError::AlreadyRequested);
+        }
+        if self.is_full() {
+            return Err(RequestError::TooManyRequests);
+        }
+        self.pending.push((request, now + self.timeout));
+        Ok(())
+    }
+
+    pub fn cancel(&mut self, index: u64) -> Option<schema::Request> {
+        self.remove(index)
+    }
+
+    /// Returns the request answered by `data`, if there was one.
+    pub fn on_data(&mut self, data: &schema::Data) -> Option<schema::Request> {
+        self.remove(data.get_index())
+    }
+
+    /// Removes and returns the requests whose deadline passed.
+    pub fn timed_out(&mut self, now: Instant) -> Vec<schema::Request> {
+        let (timed_out, pending) = self
+            .pending
+            .drain(..)
+            .partition(|(_, deadline)| *deadline <= now);
+        self.pending = pending;
+        timed_out.into_iter().map(|(request, _)| request).collect()
+    }
+
+    /// When the next request times out, if there is any pending.
+    pub fn next_deadline(&self) -> Option<Instant> {
+        self.pending.iter().map(|(_, deadline)| *deadline).min()
+    }
+
+    pub fn contains(&self, index: u64) -> bool {
+        self.pending
+            .iter()
+            .any(|(request, _)| request.get_index() == index)
+    }
+
+    pub fn len(&self) -> usize {
+        self.pending.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pending.is_empty()
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.pending.len() >= self.max_requests
+    }
+
+    fn remove(&mut self, index: u64) -> Option<schema::Request> {
+        let position = self
+            .pending
+            .iter()
+            .position(|(request, _)| request.get_index() == index)?;
+        Some(self.pending.remove(position).0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(index: u64) -> schema::Request {
+        let mut request = schema::Request::new();
+        request.set_index(index);
+        request
+    }
+
+    #[test]
+    fn test_add_and_complete() {
+        let now = Instant::now();
+        let mut requests = Requests::new(2, Duration::from_secs(1));
+        requests.add(request(1), now).unwrap();
+        assert_eq!(
+            requests.add(request(1), now),
+            Err(RequestError::AlreadyRequested)
+        );
+        requests.add(request(2), now).unwrap();
+        assert_eq!(
+            requests.add(request(3), now),
+            Err(RequestError::TooManyRequests)
+        );
+
+        let mut data = schema::Data::new();
+        data.set_index(2);
+        assert_eq!(requests.on_data(&data), Some(request(2)));
+        assert_eq!(requests.on_data(&data), None);
+        assert_eq!(requests.cancel(1), Some(request(1)));
+        assert!(requests.is_empty());
+    }
+
+    #[test]
+    fn test_timeout() {
+        let now = Instant::now();
+        let mut requests = Requests::new(16, Duration::from_secs(10));
+        requests.add(request(1), now).unwrap();
+        requests
+            .add(request(2), now + Duration::from_secs(5))
+            .unwrap();
+        assert_eq!(
+            requests.next_deadline(),
+            Some(now + Duration::from_secs(10))
+        );
+
+        assert!(requests.timed_out(now + Duration::from_secs(9)).is_empty());
+        assert_eq!(
+            requests.timed_out(now + Duration::from_secs(10)),
+            vec![request(1)]
+        );
+        assert_eq!(requests.len(), 1);
+        assert_eq!(
+            requests.timed_out(now + Duration::from_secs(20)),
+            vec![request(2)]
+        );
+        assert_eq!(requests.next_deadline(), None);
+    }
+}
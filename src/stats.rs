@@ -0,0 +1,108 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use crate::protocol::{DiscoveryKey, MessageType};
+
+/// Frames sent or received, per message type.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct MessageCounts {
+    pub feed: u64,
+    pub handshake: u64,
+    pub info: u64,
+    pub have: u64,
+    pub unhave: u64,
+    pub want: u64,
+    pub unwant: u64,
+    pub request: u64,
+    pub cancel: u64,
+    pub data: u64,
+    pub extension: u64,
+}
+
+impl MessageCounts {
+    pub fn total(&self) -> u64 {
+        self.feed
+            + self.handshake
+            + self.info
+            + self.have
+            + self.unhave
+            + self.want
+            + self.unwant
+            + self.request
+            + self.cancel
+            + self.data
+            + self.extension
+    }
+
+    pub(crate) fn add(&mut self, r#type: MessageType) {
+        let count = match r#type {
+            MessageType::Feed => &mut self.feed,
+            MessageType::Handshake => &mut self.handshake,
+            MessageType::Info => &mut self.info,
+            MessageType::Have => &mut self.have,
+            MessageType::Unhave => &mut self.unhave,
+            MessageType::Want => &mut self.want,
+            MessageType::Unwant => &mut self.unwant,
+            MessageType::Request => &mut self.request,
+            MessageType::Cancel => &mut self.cancel,
+            MessageType::Data => &mut self.data,
+            MessageType::Extension => &mut self.extension,
+        };
+        *count += 1;
+    }
+}
+
+/// The messages a feed sent and handled, not counting the `Feed` message opening it.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct FeedStats {
+    /// The encoded messages, without the framing.
+    pub bytes_sent: u64,
+    /// The encoded messages, without the framing.
+    pub bytes_received: u64,
+    pub sent: MessageCounts,
+    pub received: MessageCounts,
+}
+
+/// A snapshot of the counters of a connection, see `Protocol::stats`.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct Stats {
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+    pub sent: MessageCounts,
+    pub received: MessageCounts,
+    /// Feeds opened locally and not closed.
+    pub open_feeds: usize,
+    /// Bytes of an incomplete message waiting for the rest of it.
+    pub buffered: usize,
+    /// From sending the handshake until the one of the remote arrived, `None` if the remote
+    /// was first.
+    pub handshake_latency: Option<Duration>,
+    pub feeds: HashMap<DiscoveryKey, FeedStats>,
+}
+
+/// The counters shared by the protocol and its feeds.
+#[derive(Debug, Default)]
+pub(crate) struct Counters {
+    pub(crate) bytes_sent: u64,
+    pub(crate) bytes_received: u64,
+    pub(crate) sent: MessageCounts,
+    pub(crate) received: MessageCounts,
+    pub(crate) handshake_sent: Option<Instant>,
+    pub(crate) handshake_latency: Option<Duration>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_message_counts() {
+        let mut counts = MessageCounts::default();
+        counts.add(MessageType::Have);
+        counts.add(MessageType::Have);
+        counts.add(MessageType::Extension);
+        assert_eq!(counts.have, 2);
+        assert_eq!(counts.extension, 1);
+        assert_eq!(counts.total(), 3);
+    }
+}
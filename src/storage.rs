@@ -0,0 +1,415 @@
+use std::collections::{HashMap, HashSet};
+use std::convert::TryFrom;
+use std::fmt::{self, Debug, Display, Formatter};
+use std::io;
+
+use crate::bitfield::Bitfield;
+use crate::flat_tree;
+use crate::key_pair::KeyPair;
+use crate::merkle::Node;
+use crate::protocol::Key;
+use crate::schema;
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum StorageError {
+    NotWritable,
+    MissingNode(u64),
+    InvalidHeader,
+    Io(io::ErrorKind, String),
+}
+
+impl Display for StorageError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            StorageError::NotWritable => write!(f, "Feed is not writable"),
+            StorageError::MissingNode(index) => write!(f, "Missing tree node {}", index),
+            StorageError::InvalidHeader => write!(f, "Invalid storage header"),
+            StorageError::Io(_, err) => write!(f, "IO error: {}", err),
+        }
+    }
+}
+
+impl From<io::Error> for StorageError {
+    fn from(err: io::Error) -> Self {
+        StorageError::Io(err.kind(), err.to_string())
+    }
+}
+
+/// Where a feed keeps its blocks, tree nodes and signatures.
+///
+/// Implementations only read and write entries, appending blocks, storing verified `Data` and
+/// building `Data` for requests are provided on top of that.
+pub trait FeedStorage: Debug {
+    /// Needs the secret key to append blocks.
+    fn key_pair(&self) -> &KeyPair;
+
+    /// The length of the feed, as far as it is known.
+    fn len(&self) -> u64;
+
+    /// The blocks that are stored locally.
+    fn bitfield(&self) -> &Bitfield;
+
+    fn block(&self, index: u64) -> Result<Option<Vec<u8>>, StorageError>;
+
+    fn node(&self, index: u64) -> Result<Option<Node>, StorageError>;
+
+    /// The signature of the roots of the tree of `index + 1` blocks.
+    fn signature(&self, index: u64) -> Result<Option<Vec<u8>>, StorageError>;
+
+    fn write_block(&mut self, index: u64, value: &[u8]) -> Result<(), StorageError>;
+
+    fn write_node(&mut self, node: &Node) -> Result<(), StorageError>;
+
+    /// Stores the signature of the roots of the tree of `index + 1` blocks, extending `len()`
+    /// if needed.
+    fn write_signature(&mut self, index: u64, signature: &[u8]) -> Result<(), StorageError>;
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn key(&self) -> &Key {
+        self.key_pair().key()
+    }
+
+    fn is_writable(&self) -> bool {
+        self.key_pair().is_writable()
+    }
+
+    fn has(&self, index: u64) -> bool {
+        self.bitfield().get(index)
+    }
+
+    /// The roots of the tree of `len()` blocks.
+    fn roots(&self) -> Result<Vec<Node>, StorageError> {
+        flat_tree::full_roots(2 * self.len())
+            .into_iter()
+            .map(|index| self.node(index)?.ok_or(StorageError::MissingNode(index)))
+            .collect()
+    }
+
+    /// Appends a block and signs the new roots, returns the index of the block.
+    fn append(&mut self, value: &[u8]) -> Result<u64, StorageError> {
+        if !self.is_writable() {
+            return Err(StorageError::NotWritable);
+        }
+
+        let index = self.len();
+        let mut node = Node::leaf(index, value);
+        self.write_block(index, value)?;
+        loop {
+            let sibling = flat_tree::sibling(node.index);
+            if sibling > node.index {
+                break;
+            }
+            let parent = match self.node(sibling)? {
+                Some(left) => Node::parent(&left, &node),
+                None => break,
+            };
+            self.write_node(&node)?;
+            node = parent;
+        }
+        self.write_node(&node)?;
+
+        let roots = flat_tree::full_roots(2 * (index + 1))
+            .into_iter()
+            .map(|index| self.node(index)?.ok_or(StorageError::MissingNode(index)))
+            .collect::<Result<Vec<_>, _>>()?;
+        let signature = self
+            .key_pair()
+            .sign_roots(&roots)
+            .ok_or(StorageError::NotWritable)?;
+        self.write_signature(index, &signature)?;
+        Ok(index)
+    }
+
+    /// Stores a block that was verified already, along with the nodes of its proof that were
+    /// verified: the path up from the block, and the roots if the proof is signed. Other nodes
+    /// the remote sent are dropped.
+    fn put(&mut self, data: &schema::Data) -> Result<(), StorageError> {
+        let mut node = Node::leaf(data.get_index(), data.get_value());
+
+        let mut proof = data
+            .get_nodes()
+            .iter()
+            .filter_map(|node| Node::try_from(node).ok())
+            .map(|node| (node.index, node))
+            .collect::<HashMap<_, _>>();
+        loop {
+            let index = flat_tree::sibling(node.index);
+            let sibling = match proof.remove(&index) {
+                Some(sibling) => sibling,
+                None => match self.node(index)? {
+                    Some(sibling) => sibling,
+                    None => break,
+                },
+            };
+            let parent = Node::parent(&node, &sibling);
+            self.write_node(&node)?;
+            self.write_node(&sibling)?;
+            node = parent;
+        }
+
+        // The proof went up to the roots, they tell the length of the feed
+        if data.has_signature() {
+            let length = proof
+                .keys()
+                .chain(Some(&node.index))
+                .map(|index| flat_tree::right_span(*index) / 2 + 1)
+                .max()
+                .unwrap();
+            for index in flat_tree::full_roots(2 * length) {
+                if let Some(root) = proof.get(&index) {
+                    self.write_node(root)?;
+                }
+            }
+            if length > self.len() {
+                self.write_signature(length - 1, data.get_signature())?;
+            }
+        }
+        self.write_node(&node)?;
+        self.write_block(data.get_index(), data.get_value())
+    }
+
+    /// The `Data` answering `request`, if the block and all the nodes proving it are stored.
+    /// Leaves out the nodes the remote has according to `request.nodes`.
+    fn data(&self, request: &schema::Request) -> Result<Option<schema::Data>, StorageError> {
+        let index = request.get_index();
+        if index >= self.len() || !self.has(index) {
+            return Ok(None);
+        }
+        let value = match self.block(index)? {
+            Some(value) => value,
+            None => return Ok(None),
+        };
+        let mut remote = HashSet::new();
+        for node in flat_tree::remote_nodes(index, request.get_nodes(), self.len()) {
+            if self.node(node)?.is_some() {
+                remote.insert(node);
+            }
+        }
+        let proof = flat_tree::proof(index, self.len(), |node| remote.contains(&node));
+
+        let mut data = schema::Data::new();
+        data.set_index(index);
+        data.set_value(value);
+        for index in proof.nodes {
+            let node = match self.node(index)? {
+                Some(node) => node,
+                None => return Ok(None),
+            };
+            let mut proof_node = schema::Data_Node::new();
+            proof_node.set_index(node.index);
+            proof_node.set_hash(node.hash.to_vec());
+            proof_node.set_size(node.size);
+            data.mut_nodes().push(proof_node);
+        }
+        if proof.needs_signature {
+            match self.signature(self.len() - 1)? {
+                Some(signature) => data.set_signature(signature),
+                None => return Ok(None),
+            }
+        }
+        Ok(Some(data))
+    }
+}
+
+/// Keeps everything in memory.
+#[derive(Clone, Debug)]
+pub struct MemoryStorage {
+    key_pair: KeyPair,
+    blocks: HashMap<u64, Vec<u8>>,
+    nodes: HashMap<u64, Node>,
+    signatures: HashMap<u64, Vec<u8>>,
+    bitfield: Bitfield,
+    length: u64,
+}
+
+impl MemoryStorage {
+    pub fn new(key_pair: KeyPair) -> Self {
+        MemoryStorage {
+            key_pair,
+            blocks: HashMap::new(),
+            nodes: HashMap::new(),
+            signatures: HashMap::new(),
+            bitfield: Bitfield::new(),
+            length: 0,
+        }
+    }
+}
+
+impl FeedStorage for MemoryStorage {
+    fn key_pair(&self) -> &KeyPair {
+        &self.key_pair
+    }
+
+    fn len(&self) -> u64 {
+        self.length
+    }
+
+    fn bitfield(&self) -> &Bitfield {
+        &self.bitfield
+    }
+
+    fn block(&self, index: u64) -> Result<Option<Vec<u8>>, StorageError> {
+        Ok(self.blocks.get(&index).cloned())
+    }
+
+    fn node(&self, index: u64) -> Result<Option<Node>, StorageError> {
+        Ok(self.nodes.get(&index).cloned())
+    }
+
+    fn signature(&self, index: u64) -> Result<Option<Vec<u8>>, StorageError> {
+        Ok(self.signatures.get(&index).cloned())
+    }
+
+    fn write_block(&mut self, index: u64, value: &[u8]) -> Result<(), StorageError> {
+        self.blocks.insert(index, value.to_vec());
+        self.bitfield.set(index, true);
+        Ok(())
+    }
+
+    fn write_node(&mut self, node: &Node) -> Result<(), StorageError> {
+        self.nodes.insert(node.index, node.clone());
+        Ok(())
+    }
+
+    fn write_signature(&mut self, index: u64, signature: &[u8]) -> Result<(), StorageError> {
+        self.signatures.insert(index, signature.to_vec());
+        self.length = self.length.max(index + 1);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::merkle::Verifier;
+
+    fn request(index: u64) -> schema::Request {
+        let mut request = schema::Request::new();
+        request.set_index(index);
+        request
+    }
+
+    #[test]
+    fn test_append() {
+        sodiumoxide::init().unwrap();
+        let key_pair = KeyPair::generate();
+
+        let mut storage = MemoryStorage::new(key_pair.key().clone().into());
+        assert_eq!(storage.append(b"foo"), Err(StorageError::NotWritable));
+
+        let mut storage = MemoryStorage::new(key_pair);
+        for (i, value) in [&b"foo"[..], b"bar", b"baz"].iter().enumerate() {
+            assert_eq!(storage.append(value), Ok(i as u64));
+        }
+        assert_eq!(storage.len(), 3);
+        assert_eq!(storage.block(1), Ok(Some(b"bar".to_vec())));
+
+        let leaves = [
+            Node::leaf(0, b"foo"),
+            Node::leaf(1, b"bar"),
+            Node::leaf(2, b"baz"),
+        ];
+        assert_eq!(
+            storage.roots(),
+            Ok(vec![
+                Node::parent(&leaves[0], &leaves[1]),
+                leaves[2].clone()
+            ])
+        );
+    }
+
+    #[test]
+    fn test_replicate() {
+        sodiumoxide::init().unwrap();
+        let key_pair = KeyPair::generate();
+        let key = key_pair.key().clone();
+
+        let mut writer = MemoryStorage::new(key_pair);
+        for value in &[&b"foo"[..], b"bar", b"baz", b"qux", b"quux"] {
+            writer.append(value).unwrap();
+        }
+
+        let mut verifier = Verifier::new(key.clone());
+        let mut replica = MemoryStorage::new(key.into());
+        for index in &[3, 0, 4] {
+            let data = writer.data(&request(*index)).unwrap().unwrap();
+            verifier.verify(&data).unwrap();
+            replica.put(&data).unwrap();
+        }
+        assert_eq!(replica.len(), 5);
+        assert_eq!(replica.roots(), writer.roots());
+        assert_eq!(replica.block(3), Ok(Some(b"qux".to_vec())));
+        assert!(!replica.has(1));
+        assert_eq!(replica.data(&request(1)), Ok(None));
+
+        // The replica can serve what it has
+        let mut verifier = Verifier::new(replica.key().clone());
+        let data = replica.data(&request(0)).unwrap().unwrap();
+        verifier.verify(&data).unwrap();
+    }
+
+    #[test]
+    fn test_data() {
+        sodiumoxide::init().unwrap();
+        let key_pair = KeyPair::generate();
+        let key = key_pair.key().clone();
+
+        let mut writer = MemoryStorage::new(key_pair);
+        for value in &[&b"foo"[..], b"bar", b"baz", b"qux", b"quux"] {
+            writer.append(value).unwrap();
+        }
+        let nodes = |data: schema::Data| {
+            data.get_nodes()
+                .iter()
+                .map(|node| node.get_index())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(nodes(writer.data(&request(0)).unwrap().unwrap()), [2, 5, 8]);
+
+        // The remote has the sibling of the block
+        let mut partial = request(0);
+        partial.set_nodes(0b10);
+        assert_eq!(nodes(writer.data(&partial).unwrap().unwrap()), [5, 8]);
+        // The remote has the block's node itself
+        partial.set_nodes(1);
+        let data = writer.data(&partial).unwrap().unwrap();
+        assert!(!data.has_signature());
+        assert_eq!(nodes(data), Vec::<u64>::new());
+
+        // A block past the known length
+        let mut sparse = MemoryStorage::new(key.into());
+        sparse.write_block(7, b"foo").unwrap();
+        assert_eq!(sparse.data(&request(7)), Ok(None));
+    }
+
+    #[test]
+    fn test_put_drops_unverified_nodes() {
+        sodiumoxide::init().unwrap();
+        let key_pair = KeyPair::generate();
+        let key = key_pair.key().clone();
+
+        let mut writer = MemoryStorage::new(key_pair);
+        for value in &[&b"foo"[..], b"bar", b"baz", b"qux", b"quux"] {
+            writer.append(value).unwrap();
+        }
+
+        // Leaf 0 is neither on the path of block 3 nor a root
+        let mut data = writer.data(&request(3)).unwrap().unwrap();
+        let mut forged = schema::Data_Node::new();
+        forged.set_index(0);
+        forged.set_hash(vec![0; 32]);
+        forged.set_size(3);
+        data.mut_nodes().push(forged);
+
+        let mut verifier = Verifier::new(key.clone());
+        verifier.verify(&data).unwrap();
+        let mut replica = MemoryStorage::new(key.into());
+        replica.put(&data).unwrap();
+        assert_eq!(replica.node(0), Ok(None));
+        assert_eq!(replica.roots(), writer.roots());
+        assert_eq!(replica.node(4), writer.node(4));
+    }
+}
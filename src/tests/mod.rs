@@ -1,15 +1,28 @@
 mod protocol_pair;
 
+use std::cell::RefCell;
+use std::convert::TryInto;
 use std::ops::Deref;
+use std::rc::Rc;
+use std::sync::{Arc, Mutex};
 use std::thread;
+use std::time::{Duration, Instant};
 
 use once_cell::sync::Lazy;
 use slog::{Drain, Logger};
 use slog_scope::GlobalLoggerGuard;
+use sodiumoxide::crypto::sign;
 
-use crate::protocol::{FeedOptions, Id, Key, ProtocolOpts};
+use crate::extension::{ConnectionExtension, Extension, ExtensionConnection, ExtensionFeed};
+use crate::key_pair::KeyPair;
+use crate::merkle::{tree_hash, Node, Verifier, VerifyError};
+use crate::protocol::{
+    ConnectionKey, DiscoveryKey, FeedError, FeedOptions, Id, Key, Message, ProtocolBuilder,
+};
+use crate::remote_info::RemoteInfo;
+use crate::storage::{FeedStorage, MemoryStorage};
 use crate::tests::protocol_pair::ProtocolPair;
-use crate::FeedEvent;
+use crate::{schema, FeedEvent, Info};
 
 const KEY: Key = Key(*b"01234567890123456789012345678901");
 const OTHER_KEY: Key = Key(*b"12345678901234567890123456789012");
@@ -50,15 +63,16 @@ fn init() {
 fn basic() {
     init();
 
-    let opts = ProtocolOpts::default();
-    let mut pp = ProtocolPair::new(&opts, &opts);
+    let builder = ProtocolBuilder::new();
+    let mut pp = ProtocolPair::new(&builder, &builder);
 
     let feed_opts = FeedOptions {
         discovery_key: None,
+        ..Default::default()
     };
 
-    pp.a.protocol.feed(&KEY, feed_opts.clone());
-    pp.b.protocol.feed(&KEY, feed_opts.clone());
+    pp.a.protocol.feed(&KEY, feed_opts.clone()).unwrap();
+    pp.b.protocol.feed(&KEY, feed_opts.clone()).unwrap();
 
     pp.run();
 
@@ -85,24 +99,30 @@ fn basic_with_early_messages() {
 
     init();
 
-    let opts = ProtocolOpts::default();
-    let mut pp = ProtocolPair::new(&opts, &opts);
+    let builder = ProtocolBuilder::new();
+    let mut pp = ProtocolPair::new(&builder, &builder);
 
     let feed_opts = FeedOptions {
         discovery_key: None,
+        ..Default::default()
     };
 
     pp.run();
-    pp.a.protocol.feed(&KEY, feed_opts.clone());
+    pp.a.protocol.feed(&KEY, feed_opts.clone()).unwrap();
     pp.run();
-    pp.b.protocol.feed(&KEY, feed_opts.clone());
+    pp.b.protocol.feed(&KEY, feed_opts.clone()).unwrap();
     pp.run();
 
     assert_eq!(
         pp.a.feed_events.borrow()[..],
         vec![FeedEvent::Handshake][..]
     );
-    assert_eq!(pp.b.feed_events.borrow()[..], vec![][..]);
+    // The handshake of `a` arrives while `b` waits for the key, it is held back until `b` opens
+    //  the feed instead of being dropped.
+    assert_eq!(
+        pp.b.feed_events.borrow()[..],
+        vec![FeedEvent::Handshake][..]
+    );
 
     assert_eq!(pp.a.sent.borrow().len(), 2);
     assert_eq!(pp.b.sent.borrow().len(), 2);
@@ -121,40 +141,1045 @@ fn basic_with_handshake_options() {
     .as_bytes()
     .to_vec();
 
-    let opts_a = ProtocolOpts {
-        id: Some(Id([b'a'; 32])),
-        live: Some(true),
-        user_data: Some(data.clone()),
+    let builder_a = ProtocolBuilder::new()
+        .id(Id([b'a'; 32]))
+        .live(true)
+        .user_data(data.clone());
+    let builder_b = ProtocolBuilder::new()
+        .id(Id([b'b'; 32]))
+        .live(false)
+        .ack(true);
+    let mut pp = ProtocolPair::new(&builder_a, &builder_b);
+
+    pp.a.protocol.feed(&KEY, FeedOptions::default()).unwrap();
+    pp.b.protocol.feed(&KEY, FeedOptions::default()).unwrap();
+
+    pp.run();
+
+    assert_eq!(pp.a.protocol.id(), &Id([b'a'; 32]));
+    assert_eq!(pp.a.protocol.live(), true);
+    assert_eq!(pp.a.protocol.ack(), false);
+    assert_eq!(pp.a.protocol.user_data(), Some(&data[..]));
+    assert_eq!(
+        pp.a.protocol.remote(),
+        Some(RemoteInfo {
+            id: Id([b'b'; 32]),
+            live: false,
+            ack: true,
+            user_data: None,
+            extensions: vec![],
+        })
+    );
+
+    assert_eq!(pp.b.protocol.id(), &Id([b'b'; 32]));
+    assert_eq!(pp.b.protocol.live(), false);
+    assert_eq!(pp.b.protocol.ack(), true);
+    assert_eq!(pp.b.protocol.user_data(), None);
+    assert_eq!(
+        pp.b.protocol.remote(),
+        Some(RemoteInfo {
+            id: Id([b'a'; 32]),
+            live: true,
+            ack: false,
+            user_data: Some(data),
+            extensions: vec![],
+        })
+    );
+}
+
+#[test]
+fn backpressure() {
+    init();
+
+    let builder = ProtocolBuilder::new().high_water_mark(64);
+    let mut pp = ProtocolPair::new(&builder, &builder);
+    pp.a.would_block.set(true);
+
+    pp.a.protocol.feed(&KEY, FeedOptions::default()).unwrap();
+    pp.b.protocol.feed(&KEY, FeedOptions::default()).unwrap();
+    pp.run();
+
+    let queued: usize = pp.a.sent.borrow().iter().map(Vec::len).sum();
+    assert_eq!(pp.a.protocol.buffered_amount(), queued);
+    assert_eq!(pp.b.protocol.buffered_amount(), 0);
+    assert!(queued >= 64);
+
+    assert!(!pp.a.protocol.push(&mut [0u8; 1]));
+    assert_eq!(pp.a.protocol.buffered_amount(), queued + 1);
+
+    pp.a.protocol.drain(1);
+    assert_eq!(
+        pp.a.feed_events.borrow()[..],
+        vec![FeedEvent::Handshake][..]
+    );
+
+    pp.a.protocol.drain(queued);
+    assert_eq!(pp.a.protocol.buffered_amount(), 0);
+    assert_eq!(
+        pp.a.feed_events.borrow()[..],
+        vec![FeedEvent::Handshake, FeedEvent::Drain][..]
+    );
+}
+
+#[test]
+fn pause_and_resume() {
+    init();
+
+    let builder = ProtocolBuilder::new();
+    let mut pp = ProtocolPair::new(&builder, &builder);
+
+    pp.b.protocol.pause();
+    assert!(pp.b.protocol.is_paused());
+    assert_eq!(pp.b.protocol._write(&mut [0u8; 3]), 0);
+
+    pp.a.protocol.feed(&KEY, FeedOptions::default()).unwrap();
+    pp.b.protocol.feed(&KEY, FeedOptions::default()).unwrap();
+    pp.run();
+
+    assert_eq!(
+        pp.a.feed_events.borrow()[..],
+        vec![FeedEvent::Handshake][..]
+    );
+    assert_eq!(pp.b.feed_events.borrow()[..], vec![][..]);
+
+    pp.b.protocol.resume();
+    pp.run();
+
+    assert_eq!(
+        pp.b.feed_events.borrow()[..],
+        vec![FeedEvent::Handshake][..]
+    );
+}
+
+#[test]
+fn have_and_want() {
+    init();
+
+    let builder = ProtocolBuilder::new();
+    let mut pp = ProtocolPair::new(&builder, &builder);
+
+    let a = pp.a.protocol.feed(&KEY, FeedOptions::default()).unwrap();
+    let b = pp.b.protocol.feed(&KEY, FeedOptions::default()).unwrap();
+    pp.run();
+
+    let mut have = schema::Have::new();
+    have.set_start(3);
+    have.set_length(2);
+    assert!(a.have(have.clone()));
+    let mut want = schema::Want::new();
+    want.set_start(0);
+    want.set_length(10);
+    assert!(b.want(want.clone()));
+    pp.run();
+
+    assert!(!b.remote_has(2));
+    assert!(b.remote_has(3));
+    assert!(b.remote_has(4));
+    assert!(!b.remote_has(5));
+    assert!((0..10).all(|i| a.remote_wants(i)));
+    assert!(!a.remote_wants(10));
+
+    a.close();
+    assert!(a.is_closed());
+    assert!(!a.have(have.clone()));
+
+    assert_eq!(
+        pp.a.feed_events.borrow()[..],
+        vec![
+            FeedEvent::Handshake,
+            FeedEvent::Message(Message::Want(want))
+        ][..]
+    );
+    assert_eq!(
+        pp.b.feed_events.borrow()[..],
+        vec![
+            FeedEvent::Handshake,
+            FeedEvent::Message(Message::Have(have))
+        ][..]
+    );
+}
+
+#[test]
+fn request_and_data() {
+    init();
+
+    let builder = ProtocolBuilder::new();
+    let mut pp = ProtocolPair::new(&builder, &builder);
+
+    let feed_opts = FeedOptions {
+        request_timeout: Some(Duration::from_secs(10)),
         ..Default::default()
     };
-    let opts_b = ProtocolOpts {
-        id: Some(Id([b'b'; 32])),
-        live: Some(false),
-        ack: Some(true),
-        ..Default::default()
+    let a = pp.a.protocol.feed(&KEY, feed_opts).unwrap();
+    let b = pp.b.protocol.feed(&KEY, FeedOptions::default()).unwrap();
+    pp.run();
+
+    let now = Instant::now();
+    let mut request = schema::Request::new();
+    request.set_index(3);
+    assert_eq!(a.request(request.clone(), now), Ok(true));
+    let mut other_request = schema::Request::new();
+    other_request.set_index(4);
+    assert_eq!(a.request(other_request.clone(), now), Ok(true));
+    pp.run();
+
+    let mut data = schema::Data::new();
+    data.set_index(3);
+    data.set_value(b"foo".to_vec());
+    assert!(b.data(data.clone()));
+    pp.run();
+
+    a.tick(now + Duration::from_secs(5));
+    assert_eq!(a.pending_requests(), 1);
+    a.tick(now + Duration::from_secs(10));
+    assert_eq!(a.pending_requests(), 0);
+    pp.run();
+
+    let mut cancel = schema::Cancel::new();
+    cancel.set_index(4);
+    assert_eq!(
+        pp.a.feed_events.borrow()[..],
+        vec![
+            FeedEvent::Handshake,
+            FeedEvent::Message(Message::Data(data)),
+            FeedEvent::RequestComplete(request.clone()),
+            FeedEvent::RequestTimeout(other_request.clone()),
+        ][..]
+    );
+    assert_eq!(
+        pp.b.feed_events.borrow()[..],
+        vec![
+            FeedEvent::Handshake,
+            FeedEvent::Message(Message::Request(request)),
+            FeedEvent::Message(Message::Request(other_request)),
+            FeedEvent::Message(Message::Cancel(cancel)),
+        ][..]
+    );
+}
+
+struct CaptureDrain(Arc<Mutex<Vec<String>>>);
+
+impl Drain for CaptureDrain {
+    type Ok = ();
+    type Err = slog::Never;
+
+    fn log(&self, record: &slog::Record, _: &slog::OwnedKVList) -> Result<(), slog::Never> {
+        self.0.lock().unwrap().push(record.msg().to_string());
+        Ok(())
+    }
+}
+
+#[test]
+fn payloads_not_logged() {
+    init();
+
+    let logs = Arc::new(Mutex::new(Vec::new()));
+    let logger = Logger::root(CaptureDrain(logs.clone()), slog::o!());
+    let builder = ProtocolBuilder::new()
+        .logger(logger)
+        .user_data(vec![0xab; 8]);
+    let mut pp = ProtocolPair::new(&builder, &builder);
+
+    let a = pp.a.protocol.feed(&KEY, FeedOptions::default()).unwrap();
+    let b = pp.b.protocol.feed(&KEY, FeedOptions::default()).unwrap();
+    pp.run();
+    let mut request = schema::Request::new();
+    request.set_index(0);
+    a.request(request, Instant::now()).unwrap();
+    pp.run();
+    let mut data = schema::Data::new();
+    data.set_index(0);
+    data.set_value(vec![0xab; 8]);
+    assert!(b.data(data));
+    pp.run();
+
+    let logs = logs.lock().unwrap();
+    assert!(logs.iter().any(|log| log.starts_with("_onmessage(")));
+    assert_eq!(
+        logs.iter().any(|log| log.contains("171, 171")),
+        cfg!(feature = "log-payloads")
+    );
+
+    // Not even with `log-payloads`, the key gives read access to the feed
+    let key_hex = KEY.to_string();
+    let key_bytes = format!("{:?}", &KEY.0[..4]);
+    let key_bytes = key_bytes.trim_end_matches(']');
+    assert!(!logs
+        .iter()
+        .any(|log| log.contains(&key_hex) || log.contains(key_bytes)));
+}
+
+#[test]
+fn verify_data() {
+    init();
+
+    let builder = ProtocolBuilder::new();
+    let mut pp = ProtocolPair::new(&builder, &builder);
+
+    let a = pp.a.protocol.feed(&KEY, FeedOptions::default()).unwrap();
+    let b = pp.b.protocol.feed(&KEY, FeedOptions::default()).unwrap();
+    pp.run();
+
+    let (public_key, secret_key) = sign::gen_keypair();
+    a.set_verifier(Verifier::new(Key(public_key.0)));
+
+    let leaf = Node::leaf(0, b"foo");
+    let signature = sign::sign_detached(&tree_hash(&[leaf]), &secret_key);
+    let mut data = schema::Data::new();
+    data.set_index(0);
+    data.set_value(b"foo".to_vec());
+    data.set_signature(signature.as_ref().to_vec());
+    b.data(data.clone());
+    let mut tampered = data.clone();
+    tampered.set_value(b"bar".to_vec());
+    b.data(tampered);
+    pp.run();
+
+    assert_eq!(
+        pp.a.feed_events.borrow()[..],
+        vec![
+            FeedEvent::Handshake,
+            FeedEvent::Message(Message::Data(data)),
+            FeedEvent::InvalidData(0, VerifyError::HashMismatch),
+        ][..]
+    );
+}
+
+#[test]
+fn replicate() {
+    init();
+
+    let key_pair = KeyPair::generate();
+    let key = key_pair.key().clone();
+    let builder = ProtocolBuilder::new();
+    let mut pp = ProtocolPair::new(&builder, &builder);
+
+    let a = pp.a.protocol.feed(&key, FeedOptions::default()).unwrap();
+    let b = pp.b.protocol.feed(&key, FeedOptions::default()).unwrap();
+    a.set_storage(MemoryStorage::new(key_pair)).unwrap();
+    b.set_storage(MemoryStorage::new(key.clone().into()))
+        .unwrap();
+    assert!(a.is_writable());
+    assert!(!b.is_writable());
+    pp.run();
+
+    let values = [&b"foo"[..], b"bar", b"baz"];
+    for value in &values {
+        assert_eq!(a.append(value), Ok(true));
+    }
+    pp.run();
+
+    let missing = b.missing().into_iter().flatten().collect::<Vec<_>>();
+    assert_eq!(missing, vec![0, 1, 2]);
+    for index in missing {
+        let mut request = schema::Request::new();
+        request.set_index(index);
+        b.request(request, Instant::now()).unwrap();
+    }
+    pp.run();
+
+    b.with_storage(|storage| {
+        assert_eq!(storage.len(), 3);
+        for (index, value) in values.iter().enumerate() {
+            assert_eq!(storage.block(index as u64), Ok(Some(value.to_vec())));
+        }
+    })
+    .unwrap();
+    assert_eq!(b.pending_requests(), 0);
+    let events = pp.b.feed_events.borrow();
+    assert_eq!(
+        events
+            .iter()
+            .filter(|event| matches!(event, FeedEvent::RequestComplete(_)))
+            .count(),
+        3
+    );
+    assert!(!events
+        .iter()
+        .any(|event| matches!(event, FeedEvent::InvalidData(..))));
+}
+
+#[test]
+fn end_when_not_live() {
+    init();
+
+    let builder = ProtocolBuilder::new();
+    let mut pp = ProtocolPair::new(&builder, &builder);
+
+    let a = pp.a.protocol.feed(&KEY, FeedOptions::default()).unwrap();
+    let b = pp.b.protocol.feed(&KEY, FeedOptions::default()).unwrap();
+    pp.run();
+
+    let done = Info {
+        uploading: true,
+        downloading: false,
+    };
+    assert!(a.info(done));
+    pp.run();
+    assert_eq!(b.remote_info(), done);
+    assert!(!b.is_finished());
+    assert!(!pp.b.feed_events.borrow().contains(&FeedEvent::End));
+
+    assert!(b.info(done));
+    pp.run();
+    assert!(a.is_finished());
+    assert_eq!(pp.a.feed_events.borrow().last(), Some(&FeedEvent::End));
+    assert_eq!(pp.b.feed_events.borrow().last(), Some(&FeedEvent::End));
+
+    // Closed feeds don't send anything
+    assert!(!a.info(Info::default()));
+}
+
+#[test]
+fn end_on_local_change() {
+    init();
+
+    let builder = ProtocolBuilder::new();
+    let mut pp = ProtocolPair::new(&builder, &builder);
+
+    let a = pp.a.protocol.feed(&KEY, FeedOptions::default()).unwrap();
+    let b = pp.b.protocol.feed(&KEY, FeedOptions::default()).unwrap();
+    pp.run();
+
+    assert!(b.set_downloading(false));
+    pp.run();
+    assert!(!pp.a.feed_events.borrow().contains(&FeedEvent::End));
+
+    // Nothing else arrives after the local side stops downloading
+    assert!(a.set_downloading(false));
+    assert_eq!(pp.a.feed_events.borrow().last(), Some(&FeedEvent::End));
+
+    // Closing the last feed that is not finished ends too
+    let mut pp = ProtocolPair::new(&builder, &builder);
+    let a = pp.a.protocol.feed(&KEY, FeedOptions::default()).unwrap();
+    let a_other =
+        pp.a.protocol
+            .feed(&OTHER_KEY, FeedOptions::default())
+            .unwrap();
+    let b = pp.b.protocol.feed(&KEY, FeedOptions::default()).unwrap();
+    pp.b.protocol
+        .feed(&OTHER_KEY, FeedOptions::default())
+        .unwrap();
+    pp.run();
+
+    assert!(a.set_downloading(false));
+    assert!(b.set_downloading(false));
+    pp.run();
+    assert!(!pp.a.feed_events.borrow().contains(&FeedEvent::End));
+
+    a_other.close();
+    assert_eq!(pp.a.feed_events.borrow().last(), Some(&FeedEvent::End));
+
+    // Feeds only the remote opened don't count
+    let mut pp = ProtocolPair::new(&builder, &builder);
+    let a = pp.a.protocol.feed(&KEY, FeedOptions::default()).unwrap();
+    let b = pp.b.protocol.feed(&KEY, FeedOptions::default()).unwrap();
+    let b_other =
+        pp.b.protocol
+            .feed(&OTHER_KEY, FeedOptions::default())
+            .unwrap();
+    pp.run();
+
+    assert!(a.set_downloading(false));
+    assert!(b.set_downloading(false));
+    assert!(b_other.set_downloading(false));
+    pp.run();
+    assert_eq!(pp.a.feed_events.borrow().last(), Some(&FeedEvent::End));
+}
+
+#[test]
+fn live_does_not_end() {
+    init();
+
+    let builder = ProtocolBuilder::new();
+    let live_builder = ProtocolBuilder::new().live(true);
+    let mut pp = ProtocolPair::new(&live_builder, &builder);
+
+    let a = pp.a.protocol.feed(&KEY, FeedOptions::default()).unwrap();
+    let b = pp.b.protocol.feed(&KEY, FeedOptions::default()).unwrap();
+    pp.run();
+
+    let done = Info {
+        uploading: true,
+        downloading: false,
+    };
+    a.info(done);
+    b.info(done);
+    pp.run();
+
+    assert!(a.is_finished());
+    assert!(!pp.a.feed_events.borrow().contains(&FeedEvent::End));
+    assert!(!pp.b.feed_events.borrow().contains(&FeedEvent::End));
+}
+
+#[test]
+fn uploading_and_downloading() {
+    init();
+
+    let key_pair = KeyPair::generate();
+    let key = key_pair.key().clone();
+    let builder = ProtocolBuilder::new().live(true);
+    let mut pp = ProtocolPair::new(&builder, &builder);
+
+    let a = pp.a.protocol.feed(&key, FeedOptions::default()).unwrap();
+    let b = pp.b.protocol.feed(&key, FeedOptions::default()).unwrap();
+    let mut storage = MemoryStorage::new(key_pair);
+    storage.append(b"foo").unwrap();
+    a.set_storage(storage).unwrap();
+    pp.run();
+    pp.b.feed_events.borrow_mut().clear();
+
+    // Nothing is sent if nothing changed
+    let sent = pp.a.sent.borrow().len();
+    assert!(a.set_uploading(true));
+    assert_eq!(pp.a.sent.borrow().len(), sent);
+
+    assert!(a.set_uploading(false));
+    assert!(a.set_uploading(false));
+    pp.run();
+    assert!(!b.remote_uploading());
+    assert!(b.remote_downloading());
+
+    let mut request = schema::Request::new();
+    request.set_index(0);
+    b.request(request, Instant::now()).unwrap();
+    b.set_downloading(false);
+    pp.run();
+    assert!(!a.remote_downloading());
+    assert_eq!(b.pending_requests(), 1);
+
+    let info = |uploading, downloading| {
+        let mut info = schema::Info::new();
+        info.set_uploading(uploading);
+        info.set_downloading(downloading);
+        FeedEvent::Message(Message::Info(info))
     };
-    let mut pp = ProtocolPair::new(&opts_a, &opts_b);
-
-    pp.a.protocol.feed(&KEY, FeedOptions::default());
-    pp.b.protocol.feed(&KEY, FeedOptions::default());
-
-    pp.run();
-
-    assert_eq!(pp.a.protocol.id, Id([b'a'; 32]));
-    assert_eq!(pp.a.protocol.live, true);
-    assert_eq!(pp.a.protocol.ack, false);
-    assert_eq!(pp.a.protocol.user_data, Some(data.clone()));
-    assert_eq!(*pp.a.protocol.remote_id.borrow(), Some(Id([b'b'; 32])));
-    assert_eq!(pp.a.protocol.remote_live.get(), Some(false));
-    assert_eq!(*pp.a.protocol.remote_user_data.borrow(), None);
-    assert_eq!(pp.a.protocol.remote_ack.get(), Some(true));
-
-    assert_eq!(pp.b.protocol.id, Id([b'b'; 32]));
-    assert_eq!(pp.b.protocol.live, false);
-    assert_eq!(pp.b.protocol.ack, true);
-    assert_eq!(pp.b.protocol.user_data, None);
-    assert_eq!(*pp.b.protocol.remote_id.borrow(), Some(Id([b'a'; 32])));
-    assert_eq!(pp.b.protocol.remote_live.get(), Some(true));
-    assert_eq!(*pp.b.protocol.remote_user_data.borrow(), Some(data));
-    assert_eq!(pp.b.protocol.remote_ack.get(), Some(false));
+    assert_eq!(
+        pp.b.feed_events.borrow()[..],
+        vec![info(false, true), FeedEvent::RemoteUploading(false)][..]
+    );
+    assert_eq!(
+        pp.a.feed_events.borrow()[2..],
+        vec![info(true, false), FeedEvent::RemoteDownloading(false)][..]
+    );
+}
+
+#[test]
+fn ack() {
+    init();
+
+    let key_pair = KeyPair::generate();
+    let key = key_pair.key().clone();
+    let builder = ProtocolBuilder::new().ack(true).live(true);
+    let mut pp = ProtocolPair::new(&builder, &builder);
+
+    let a = pp.a.protocol.feed(&key, FeedOptions::default()).unwrap();
+    let b = pp.b.protocol.feed(&key, FeedOptions::default()).unwrap();
+    a.set_storage(MemoryStorage::new(key_pair)).unwrap();
+    b.set_storage(MemoryStorage::new(key.clone().into()))
+        .unwrap();
+    pp.run();
+
+    for value in &[&b"foo"[..], b"bar"] {
+        a.append(value).unwrap();
+    }
+    for index in 0..2 {
+        let mut request = schema::Request::new();
+        request.set_index(index);
+        b.request(request, Instant::now()).unwrap();
+    }
+    // Stop right after the data was sent
+    pp.a.process();
+    pp.b.process();
+    assert_eq!(a.pending_acks(), 2);
+
+    pp.run();
+    assert_eq!(a.pending_acks(), 0);
+    let acked =
+        pp.a.feed_events
+            .borrow()
+            .iter()
+            .filter_map(|event| match event {
+                FeedEvent::Acked(index) => Some(*index),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+    assert_eq!(acked, vec![0, 1]);
+}
+
+#[test]
+fn no_ack_unless_both_agree() {
+    init();
+
+    let key_pair = KeyPair::generate();
+    let key = key_pair.key().clone();
+    let builder = ProtocolBuilder::new().ack(true);
+    let mut pp = ProtocolPair::new(&builder, &ProtocolBuilder::new());
+
+    let a = pp.a.protocol.feed(&key, FeedOptions::default()).unwrap();
+    let b = pp.b.protocol.feed(&key, FeedOptions::default()).unwrap();
+    a.set_storage(MemoryStorage::new(key_pair)).unwrap();
+    pp.run();
+
+    a.append(b"foo").unwrap();
+    let mut request = schema::Request::new();
+    request.set_index(0);
+    b.request(request, Instant::now()).unwrap();
+    pp.run();
+
+    assert_eq!(a.pending_acks(), 0);
+    assert!(!pp
+        .a
+        .feed_events
+        .borrow()
+        .iter()
+        .any(|event| matches!(event, FeedEvent::Acked(_))));
+}
+
+fn different_first_feeds(builder: &ProtocolBuilder) -> ProtocolPair {
+    let mut pp = ProtocolPair::new(builder, builder);
+
+    pp.a.protocol.feed(&KEY, FeedOptions::default()).unwrap();
+    pp.b.protocol
+        .feed(&OTHER_KEY, FeedOptions::default())
+        .unwrap();
+    pp.run();
+    pp
+}
+
+#[test]
+fn different_first_feeds_encrypted() {
+    init();
+
+    let mut pp = different_first_feeds(&ProtocolBuilder::new());
+    assert_eq!(
+        pp.b.protocol.feed(&KEY, FeedOptions::default()).err(),
+        Some(FeedError::Destroyed)
+    );
+
+    // The remote feed arrived before the first local one
+    let builder = ProtocolBuilder::new();
+    let mut pp = ProtocolPair::new(&builder, &builder);
+    pp.a.protocol.feed(&KEY, FeedOptions::default()).unwrap();
+    pp.run();
+    assert_eq!(
+        pp.b.protocol.feed(&OTHER_KEY, FeedOptions::default()).err(),
+        Some(FeedError::KeyMismatch)
+    );
+    assert!(pp.b.protocol.is_destroyed());
+}
+
+fn matched_later(pp: &mut ProtocolPair) {
+    let a =
+        pp.a.protocol
+            .feed(&OTHER_KEY, FeedOptions::default())
+            .unwrap();
+    let b = pp.b.protocol.feed(&KEY, FeedOptions::default()).unwrap();
+    pp.run();
+
+    let mut have = schema::Have::new();
+    have.set_start(1);
+    assert!(a.have(have.clone()));
+    assert!(b.have(have.clone()));
+    pp.run();
+
+    for events in &[&pp.a.feed_events, &pp.b.feed_events] {
+        assert_eq!(
+            events.borrow()[..],
+            vec![
+                FeedEvent::Handshake,
+                FeedEvent::Message(Message::Have(have.clone()))
+            ][..]
+        );
+    }
+}
+
+#[test]
+fn different_first_feeds_unencrypted() {
+    init();
+
+    let builder = ProtocolBuilder::new().encrypted(false);
+    let mut pp = different_first_feeds(&builder);
+    matched_later(&mut pp);
+}
+
+#[test]
+fn different_first_feeds_fixed_connection_key() {
+    init();
+
+    let builder = ProtocolBuilder::new().connection_key(ConnectionKey::Fixed(Key(
+        *b"connection-key-0123456789abcdefg",
+    )));
+    let mut pp = different_first_feeds(&builder);
+    matched_later(&mut pp);
+}
+
+#[test]
+fn key_lookup() {
+    init();
+
+    let lookups = Rc::new(RefCell::new(Vec::new()));
+    let builder = ProtocolBuilder::new();
+    let lookup_builder = ProtocolBuilder::new().key_lookup({
+        let lookups = lookups.clone();
+        move |dk| {
+            lookups.borrow_mut().push(dk.clone());
+            if *dk == KEY.discovery_key() {
+                Some(KEY.clone())
+            } else {
+                None
+            }
+        }
+    });
+    let mut pp = ProtocolPair::new(&builder, &lookup_builder);
+
+    let a = pp.a.protocol.feed(&KEY, FeedOptions::default()).unwrap();
+    pp.a.protocol
+        .feed(&OTHER_KEY, FeedOptions::default())
+        .unwrap();
+    let mut have = schema::Have::new();
+    have.set_start(0);
+    a.have(have.clone());
+    pp.run();
+
+    assert_eq!(
+        lookups.borrow()[..],
+        [KEY.discovery_key(), OTHER_KEY.discovery_key()][..]
+    );
+    assert_eq!(
+        pp.a.feed_events.borrow()[..],
+        vec![FeedEvent::Handshake][..]
+    );
+    assert_eq!(
+        pp.b.feed_events.borrow()[..],
+        vec![
+            FeedEvent::Handshake,
+            FeedEvent::Message(Message::Have(have))
+        ][..]
+    );
+    let b = pp.b.protocol.feed(&KEY, FeedOptions::default()).unwrap();
+    assert!(b.remote_has(0));
+}
+
+#[test]
+fn key_lookup_mismatch() {
+    init();
+
+    let builder = ProtocolBuilder::new();
+    let lookup_builder = ProtocolBuilder::new().key_lookup(|_| Some(OTHER_KEY.clone()));
+    let mut pp = ProtocolPair::new(&builder, &lookup_builder);
+
+    let a = pp.a.protocol.feed(&KEY, FeedOptions::default()).unwrap();
+    let mut have = schema::Have::new();
+    have.set_start(0);
+    a.have(have);
+    pp.run();
+
+    assert!(pp.b.protocol.local_feeds().is_empty());
+    assert_eq!(pp.b.protocol.remote_feeds(), [KEY.discovery_key()]);
+    assert!(pp.b.feed_events.borrow().is_empty());
+    assert!(!pp.b.protocol.is_destroyed());
+}
+
+#[test]
+fn too_many_feeds() {
+    init();
+
+    let builder = ProtocolBuilder::new();
+    let limited_builder = ProtocolBuilder::new().max_feeds(1);
+    let mut pp = ProtocolPair::new(&builder, &limited_builder);
+
+    pp.a.protocol.feed(&KEY, FeedOptions::default()).unwrap();
+    pp.b.protocol.feed(&KEY, FeedOptions::default()).unwrap();
+    pp.run();
+    assert!(!pp.b.protocol.is_destroyed());
+
+    // Opened on channel 1
+    pp.a.protocol
+        .feed(&OTHER_KEY, FeedOptions::default())
+        .unwrap();
+    pp.run();
+    assert!(pp.b.protocol.is_destroyed());
+}
+
+struct PeerExchange {
+    /// Sent in reply to the first message
+    peers: Vec<String>,
+    received: Rc<RefCell<Vec<Vec<String>>>>,
+}
+
+impl Extension for PeerExchange {
+    type Message = Vec<String>;
+
+    fn name(&self) -> &str {
+        "peers"
+    }
+
+    fn encode(&self, message: &Vec<String>) -> Vec<u8> {
+        message.join("\n").into_bytes()
+    }
+
+    fn decode(&self, payload: &[u8]) -> Option<Vec<String>> {
+        let peers = String::from_utf8(payload.to_vec()).ok()?;
+        Some(peers.split('\n').map(str::to_owned).collect())
+    }
+
+    fn on_message(&mut self, feed: &mut dyn ExtensionFeed, message: Vec<String>) {
+        self.received.borrow_mut().push(message);
+        if !self.peers.is_empty() {
+            let payload = self.encode(&self.peers);
+            self.peers.clear();
+            assert!(feed.extension("peers", &payload));
+        }
+    }
+}
+
+#[test]
+fn extensions() {
+    init();
+
+    let a_received = Rc::new(RefCell::new(Vec::new()));
+    let b_received = Rc::new(RefCell::new(Vec::new()));
+    // The extension ids differ on both sides
+    let builder_a = ProtocolBuilder::new()
+        .extensions(vec!["a-only".to_owned(), "aa-only".to_owned()])
+        .extension("raw")
+        .register(PeerExchange {
+            peers: vec![],
+            received: a_received.clone(),
+        });
+    let builder_b = ProtocolBuilder::new()
+        .extension("b-only")
+        .extension("raw")
+        .register(PeerExchange {
+            peers: vec!["b:1".to_owned()],
+            received: b_received.clone(),
+        });
+    let mut pp = ProtocolPair::new(&builder_a, &builder_b);
+
+    let a = pp.a.protocol.feed(&KEY, FeedOptions::default()).unwrap();
+    pp.b.protocol.feed(&KEY, FeedOptions::default()).unwrap();
+    pp.run();
+
+    assert!(a.send_extension::<PeerExchange>(&vec!["a:1".to_owned(), "a:2".to_owned()]));
+    assert!(a.extension("raw", b"hello"));
+    assert!(!a.extension("a-only", b""));
+    assert!(!a.extension("missing", b""));
+    pp.run();
+
+    assert_eq!(
+        b_received.borrow()[..],
+        [vec!["a:1".to_owned(), "a:2".to_owned()]][..]
+    );
+    assert_eq!(a_received.borrow()[..], [vec!["b:1".to_owned()]][..]);
+    assert_eq!(
+        pp.b.feed_events.borrow()[..],
+        vec![
+            FeedEvent::Handshake,
+            FeedEvent::Extension("raw".to_owned(), b"hello".to_vec())
+        ][..]
+    );
+}
+
+#[test]
+fn extension_on_unopened_feed() {
+    init();
+
+    let received = Rc::new(RefCell::new(Vec::new()));
+    let builder_a = ProtocolBuilder::new()
+        .encrypted(false)
+        .register(PeerExchange {
+            peers: vec![],
+            received: Rc::new(RefCell::new(Vec::new())),
+        });
+    let builder_b = ProtocolBuilder::new()
+        .encrypted(false)
+        .register(PeerExchange {
+            peers: vec!["b:1".to_owned()],
+            received: received.clone(),
+        });
+    let mut pp = ProtocolPair::new(&builder_a, &builder_b);
+
+    let a = pp.a.protocol.feed(&KEY, FeedOptions::default()).unwrap();
+    pp.b.protocol
+        .feed(&OTHER_KEY, FeedOptions::default())
+        .unwrap();
+    pp.run();
+
+    assert!(a.send_extension::<PeerExchange>(&vec!["a:1".to_owned()]));
+    pp.run();
+    assert!(received.borrow().is_empty());
+    assert!(!pp.b.protocol.is_destroyed());
+
+    pp.b.protocol.feed(&KEY, FeedOptions::default()).unwrap();
+    pp.run();
+    assert!(a.send_extension::<PeerExchange>(&vec!["a:2".to_owned()]));
+    pp.run();
+    assert_eq!(received.borrow()[..], [vec!["a:2".to_owned()]][..]);
+}
+
+struct FeedOffers {
+    /// Sent in reply to the first message
+    offers: Vec<DiscoveryKey>,
+    received: Rc<RefCell<Vec<Vec<DiscoveryKey>>>>,
+}
+
+impl ConnectionExtension for FeedOffers {
+    type Message = Vec<DiscoveryKey>;
+
+    fn name(&self) -> &str {
+        "offers"
+    }
+
+    fn encode(&self, message: &Vec<DiscoveryKey>) -> Vec<u8> {
+        message
+            .iter()
+            .flat_map(|dk| dk.as_bytes().to_vec())
+            .collect()
+    }
+
+    fn decode(&self, payload: &[u8]) -> Option<Vec<DiscoveryKey>> {
+        let chunks = payload.chunks_exact(32);
+        if !chunks.remainder().is_empty() {
+            return None;
+        }
+        chunks.map(|dk| dk.try_into().ok()).collect()
+    }
+
+    fn on_message(&mut self, connection: &mut dyn ExtensionConnection, message: Vec<DiscoveryKey>) {
+        self.received.borrow_mut().push(message);
+        if !self.offers.is_empty() {
+            let payload = self.encode(&self.offers);
+            self.offers.clear();
+            assert!(connection.extension("offers", &payload));
+        }
+    }
+}
+
+#[test]
+fn connection_extensions() {
+    init();
+
+    let a_received = Rc::new(RefCell::new(Vec::new()));
+    let b_received = Rc::new(RefCell::new(Vec::new()));
+    let connection_key = ConnectionKey::Fixed(Key(*b"connection-key-0123456789abcdefg"));
+    let builder_a = ProtocolBuilder::new()
+        .connection_key(connection_key.clone())
+        .extension("raw")
+        .register_connection(FeedOffers {
+            offers: vec![],
+            received: a_received.clone(),
+        });
+    let builder_b = ProtocolBuilder::new()
+        .connection_key(connection_key)
+        .extension("raw")
+        .register_connection(FeedOffers {
+            offers: vec![OTHER_KEY.discovery_key()],
+            received: b_received.clone(),
+        });
+    let mut pp = ProtocolPair::new(&builder_a, &builder_b);
+
+    let offers = vec![KEY.discovery_key()];
+    assert!(!pp.a.protocol.send_extension::<FeedOffers>(&offers));
+
+    // Neither side knows the feed of the other one
+    pp.a.protocol.feed(&KEY, FeedOptions::default()).unwrap();
+    pp.b.protocol
+        .feed(&OTHER_KEY, FeedOptions::default())
+        .unwrap();
+    pp.run();
+
+    assert!(pp.a.protocol.send_extension::<FeedOffers>(&offers));
+    assert!(pp.a.protocol.extension("raw", b"hello"));
+    assert!(!pp.a.protocol.extension("missing", b""));
+    pp.run();
+
+    assert_eq!(b_received.borrow()[..], [offers][..]);
+    assert_eq!(
+        a_received.borrow()[..],
+        [vec![OTHER_KEY.discovery_key()]][..]
+    );
+    assert_eq!(
+        pp.b.feed_events.borrow()[..],
+        vec![
+            FeedEvent::Handshake,
+            FeedEvent::Extension("raw".to_owned(), b"hello".to_vec())
+        ][..]
+    );
+}
+
+#[test]
+fn connection_state() {
+    init();
+
+    let builder = ProtocolBuilder::new().connection_key(ConnectionKey::Fixed(Key(
+        *b"connection-key-0123456789abcdefg",
+    )));
+    let builder_a = builder.clone().extension("a").extension("both");
+    let builder_b = builder.id(Id([b'b'; 32])).live(true).extension("both");
+    let mut pp = ProtocolPair::new(&builder_a, &builder_b);
+
+    let a = &mut pp.a.protocol;
+    assert!(a.encrypted());
+    assert!(!a.is_destroyed());
+    assert!(!a.is_handshaken());
+    assert_eq!(a.remote_id(), None);
+    assert_eq!(a.remote_live(), None);
+    assert!(a.negotiated_extensions().is_empty());
+
+    pp.a.protocol.feed(&KEY, FeedOptions::default()).unwrap();
+    pp.b.protocol
+        .feed(&OTHER_KEY, FeedOptions::default())
+        .unwrap();
+    pp.run();
+
+    let a = &mut pp.a.protocol;
+    assert!(a.is_handshaken());
+    assert_eq!(a.remote_id(), Some(Id([b'b'; 32])));
+    assert_eq!(a.remote_live(), Some(true));
+    assert_eq!(a.negotiated_extensions(), ["both"]);
+    assert_eq!(a.local_feeds(), [KEY.discovery_key()]);
+    assert_eq!(a.remote_feeds(), [OTHER_KEY.discovery_key()]);
+
+    a.finalize();
+    assert!(a.is_destroyed());
+}
+
+#[test]
+fn stats() {
+    init();
+
+    let mut pp = ProtocolPair::new(&ProtocolBuilder::new(), &ProtocolBuilder::new());
+    let feed_a = pp.a.protocol.feed(&KEY, FeedOptions::default()).unwrap();
+    pp.b.protocol.feed(&KEY, FeedOptions::default()).unwrap();
+    pp.run();
+
+    let mut have = schema::Have::new();
+    have.set_start(0);
+    have.set_length(10);
+    feed_a.have(have);
+    pp.run();
+
+    let a = pp.a.protocol.stats();
+    let b = pp.b.protocol.stats();
+    assert_eq!(a.sent.feed, 1);
+    assert_eq!(a.sent.handshake, 1);
+    assert_eq!(a.sent.have, 1);
+    assert_eq!(b.received.feed, 1);
+    assert_eq!(b.received.have, 1);
+    assert_eq!(a.bytes_sent, b.bytes_received);
+    assert_eq!(b.bytes_sent, a.bytes_received);
+    assert_eq!(a.open_feeds, 1);
+    assert_eq!(a.buffered, 0);
+    assert!(a.handshake_latency.is_some());
+
+    let feed_stats = &a.feeds[&KEY.discovery_key()];
+    assert_eq!(feed_stats.sent.have, 1);
+    assert_eq!(feed_stats.received.have, 0);
+    assert_eq!(feed_a.stats(), *feed_stats);
+    assert_eq!(b.feeds[&KEY.discovery_key()].received.have, 1);
+    assert_eq!(
+        feed_stats.bytes_sent,
+        b.feeds[&KEY.discovery_key()].bytes_received
+    );
 }
@@ -1,10 +1,10 @@
-use std::cell::RefCell;
+use std::cell::{Cell, RefCell};
 use std::rc::Rc;
 use std::sync::mpsc;
 
 use log::trace;
 
-use crate::protocol::{Protocol, ProtocolOpts, Stream};
+use crate::protocol::{Protocol, ProtocolBuilder, Push, Stream};
 use crate::{FeedEvent, FeedEventEmitter};
 
 pub struct ProtocolPair {
@@ -13,13 +13,14 @@ pub struct ProtocolPair {
 }
 
 impl ProtocolPair {
-    pub fn new(opts_a: &ProtocolOpts, opts_b: &ProtocolOpts) -> Self {
+    pub fn new(builder_a: &ProtocolBuilder, builder_b: &ProtocolBuilder) -> Self {
+        // `None` is sent when the stream ended
         let (sender1, receiver1) = mpsc::channel();
         let (sender2, receiver2) = mpsc::channel();
 
         Self {
-            a: ProtocolX::new(opts_a, sender1, receiver2),
-            b: ProtocolX::new(opts_b, sender2, receiver1),
+            a: ProtocolX::new(builder_a, sender1, receiver2),
+            b: ProtocolX::new(builder_b, sender2, receiver1),
         }
     }
 
@@ -36,62 +37,90 @@ impl ProtocolPair {
 
 pub struct ProtocolX {
     pub protocol: Protocol<Emitter, ChannelStream>,
-    receiver: mpsc::Receiver<Vec<u8>>,
+    receiver: mpsc::Receiver<Option<Vec<u8>>>,
+    pending: Option<Vec<u8>>,
 
     pub sent: Rc<RefCell<Vec<Vec<u8>>>>,
     pub feed_events: Rc<RefCell<Vec<FeedEvent>>>,
+    pub would_block: Rc<Cell<bool>>,
 }
 
 impl ProtocolX {
     fn new(
-        protocol_opts: &ProtocolOpts,
-        sender: mpsc::Sender<Vec<u8>>,
-        receiver: mpsc::Receiver<Vec<u8>>,
+        builder: &ProtocolBuilder,
+        sender: mpsc::Sender<Option<Vec<u8>>>,
+        receiver: mpsc::Receiver<Option<Vec<u8>>>,
     ) -> Self {
         let sent = Rc::new(RefCell::new(Vec::new()));
         let feed_events = Rc::new(RefCell::new(Vec::new()));
+        let would_block = Rc::new(Cell::new(false));
         Self {
-            protocol: Protocol::new(
-                None,
-                Emitter(feed_events.clone()),
-                ChannelStream {
-                    sender,
-                    sent: sent.clone(),
-                },
-                &protocol_opts,
-            ),
+            protocol: builder
+                .clone()
+                .build(
+                    Emitter(feed_events.clone()),
+                    ChannelStream {
+                        sender,
+                        sent: sent.clone(),
+                        would_block: would_block.clone(),
+                    },
+                )
+                .unwrap(),
             receiver,
+            pending: None,
             sent,
             feed_events,
+            would_block,
         }
     }
 
-    fn process(&mut self) -> bool {
+    pub fn process(&mut self) -> bool {
         let mut got_message = false;
         loop {
-            match self.receiver.try_recv() {
-                Ok(mut bytes) => {
-                    got_message = true;
-                    trace!("Received bytes: {:?}", bytes);
-                    self.protocol._write(&mut bytes);
-                }
-                Err(_) => break,
+            let mut bytes = match self.pending.take() {
+                Some(bytes) => bytes,
+                None => match self.receiver.try_recv() {
+                    Ok(Some(bytes)) => bytes,
+                    Ok(None) => {
+                        self.protocol.finalize();
+                        return true;
+                    }
+                    Err(_) => break,
+                },
+            };
+            trace!("Received bytes: {:?}", bytes);
+            let consumed = self.protocol._write(&mut bytes);
+            if consumed < bytes.len() {
+                self.pending = Some(bytes[consumed..].to_vec());
+                break;
             }
+            got_message = true;
         }
         got_message
     }
 }
 
 pub struct ChannelStream {
-    sender: mpsc::Sender<Vec<u8>>,
+    sender: mpsc::Sender<Option<Vec<u8>>>,
     sent: Rc<RefCell<Vec<Vec<u8>>>>,
+    would_block: Rc<Cell<bool>>,
 }
 
 impl Stream for ChannelStream {
-    fn _push(&mut self, bytes: &mut [u8]) {
+    fn _push(&mut self, bytes: &mut [u8]) -> Push {
         trace!("Sending bytes: {:?}", bytes);
-        self.sender.send(bytes.to_vec()).unwrap();
+        self.sender.send(Some(bytes.to_vec())).unwrap();
         self.sent.borrow_mut().push(bytes.to_vec());
+        if self.would_block.get() {
+            Push::WouldBlock
+        } else {
+            Push::Accepted
+        }
+    }
+
+    fn _end(&mut self) {
+        trace!("Ending stream");
+        self.sender.send(None).unwrap();
     }
 }
 
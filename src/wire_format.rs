@@ -3,13 +3,14 @@ use std::io::{BufReader, Read, Write};
 use integer_encoding::{VarInt, VarIntReader, VarIntWriter};
 use protobuf::{self, parse_from_reader, Message as _, ProtobufResult};
 
+use crate::logging::{global_trace, Bytes, Payload};
 use crate::protocol::{Channel, Header, Message, MessageType};
 
 pub(crate) fn write_msg(channel: Channel, msg: &Message) -> ProtobufResult<Vec<u8>> {
-    log::trace!("write_msg({:?}, {:?})", channel, msg);
+    global_trace!("write_msg({:?}, {:?})", channel, Payload(msg));
     let mut buf = Vec::new();
     write_msg_to_writer(channel, msg, &mut buf)?;
-    log::trace!("write_msg => {:?}", buf);
+    global_trace!("write_msg => {:?}", Bytes(&buf));
     Ok(buf)
 }
 
@@ -18,13 +19,13 @@ pub(crate) fn write_msg_to_writer<W: Write>(
     msg: &Message,
     mut writer: W,
 ) -> ProtobufResult<()> {
-    log::trace!("write_msg_to_writer({:?}, {:?})", channel, msg);
+    global_trace!("write_msg_to_writer({:?}, {:?})", channel, Payload(msg));
     let message_type = MessageType::from_message(&msg);
     let header = encode_header(Header {
         channel,
         message_type,
     });
-    log::trace!(
+    global_trace!(
         "write_msg_to_writer channel: {:?}, message_type: {:?} => header: {}",
         channel,
         message_type,
@@ -32,7 +33,7 @@ pub(crate) fn write_msg_to_writer<W: Write>(
     );
 
     let len = VarInt::required_space(u64::from(header)) + get_size(msg);
-    log::trace!("write_msg_to_writer len: {}", len);
+    global_trace!("write_msg_to_writer len: {}", len);
 
     writer.write_varint(len)?;
     writer.write_varint(header)?;
@@ -40,25 +41,46 @@ pub(crate) fn write_msg_to_writer<W: Write>(
     Ok(())
 }
 
+/// The type of the encoded message at the start of `bytes`.
+pub(crate) fn peek_message_type(bytes: &[u8]) -> Option<MessageType> {
+    let (_, len_size) = u64::decode_var(bytes);
+    let (header, header_size) = u16::decode_var(bytes.get(len_size..)?);
+    if len_size == 0 || header_size == 0 {
+        return None;
+    }
+    Some(decode_header(header).message_type)
+}
+
+/// An `Extension` message, `id` is the index of the extension in the local handshake.
+pub(crate) fn extension_message(id: usize, payload: &[u8]) -> Message {
+    let mut bytes = (id as u64).encode_var_vec();
+    bytes.extend_from_slice(payload);
+    Message::Extension(bytes)
+}
+
 fn read_msg(bytes: &[u8]) -> ProtobufResult<(Channel, Message)> {
-    log::trace!("read_msg({:?})", bytes);
+    global_trace!("read_msg({:?})", Bytes(bytes));
     let mut reader = BufReader::new(bytes);
     let (channel, msg) = read_msg_from_reader(&mut reader)?;
-    log::trace!("read_msg channel: {:?}, message: {:?}", channel, msg);
+    global_trace!(
+        "read_msg channel: {:?}, message: {:?}",
+        channel,
+        Payload(&msg)
+    );
     let mut remaining = Vec::new();
     assert_eq!(reader.read_to_end(&mut remaining)?, 0);
     Ok((channel, msg))
 }
 
 fn read_msg_from_reader<R: Read>(mut reader: R) -> ProtobufResult<(Channel, Message)> {
-    log::trace!("read_msg_from_reader()");
+    global_trace!("read_msg_from_reader()");
     let len: usize = reader.read_varint()?;
     let header = reader.read_varint()?;
-    log::trace!("read_msg_from_reader len: {}, header: {:?}", len, header);
+    global_trace!("read_msg_from_reader len: {}, header: {:?}", len, header);
 
     let header_len = VarInt::required_space(header);
     let msg_len = len - header_len;
-    log::trace!(
+    global_trace!(
         "read_msg_from_reader header_len: {}, msg_len: {}",
         header_len,
         msg_len
@@ -68,7 +90,7 @@ fn read_msg_from_reader<R: Read>(mut reader: R) -> ProtobufResult<(Channel, Mess
         channel,
         message_type,
     } = decode_header(header);
-    log::trace!(
+    global_trace!(
         "read_msg_from_reader channel: {:?}, message_type: {:?}",
         channel,
         message_type
@@ -94,7 +116,11 @@ pub(crate) fn read_msg2<R: Read>(
         MessageType::Request => Message::Request(parse_from_reader(&mut reader)?),
         MessageType::Cancel => Message::Cancel(parse_from_reader(&mut reader)?),
         MessageType::Data => Message::Data(parse_from_reader(&mut reader)?),
-        MessageType::Extension => unimplemented!(),
+        MessageType::Extension => {
+            let mut bytes = Vec::new();
+            reader.read_to_end(&mut bytes)?;
+            Message::Extension(bytes)
+        }
     };
     Ok(msg)
 }
@@ -148,11 +174,12 @@ fn write_message<W: Write>(msg: &Message, mut writer: W) -> ProtobufResult<()> {
         Message::Request(m) => m.write_to_writer(&mut writer),
         Message::Cancel(m) => m.write_to_writer(&mut writer),
         Message::Data(m) => m.write_to_writer(&mut writer),
-        Message::Extension(bytes) => unimplemented!(),
+        Message::Extension(bytes) => Ok(writer.write_all(bytes)?),
     }
 }
 
-fn get_size(msg: &Message) -> usize {
+/// The size of the encoded message, without the framing.
+pub(crate) fn get_size(msg: &Message) -> usize {
     fn compute_size<M: protobuf::Message>(m: &M) -> usize {
         m.compute_size() as usize
     }
@@ -223,4 +250,19 @@ mod tests {
         let result = read_msg(bytes).unwrap();
         assert_eq!(result, expected);
     }
+
+    #[test]
+    fn test_peek_message_type() {
+        let bytes = &[0x06, 0xa2, 0x05, 0x08, 0x00, 0x10, 0x01];
+        assert_eq!(peek_message_type(bytes), Some(MessageType::Info));
+        assert_eq!(peek_message_type(&bytes[..1]), None);
+    }
+
+    #[test]
+    fn test_extension() {
+        let bytes = &[0x04, 0xaf, 0x05, 0x01, 0xaa];
+        let msg = Message::Extension(vec![0x01, 0xaa]);
+        assert_eq!(write_msg(Channel(42), &msg).unwrap(), bytes);
+        assert_eq!(read_msg(bytes).unwrap(), (Channel(42), msg));
+    }
 }